@@ -0,0 +1,243 @@
+use std::{
+    fmt::Display,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    chat::ChatChannel,
+    codec::{self, MessagesCodec},
+    math::Vector2,
+    messages::{
+        self, Ack, CompressionAlgorithm, HandshakeError, HandshakeRequest, IncomingChatMessage,
+        InformNearbyClients, Message, OutgoingChatMessage, PlayerRenamed, PositionUpdate, Request,
+        RequestError, RequestMessage, ResumeSession, ServerNotice, ServerStatusUpdate,
+        SessionToken, SetMatchmakingPassword,
+    },
+};
+
+/// Connection to a matchmaking server
+pub struct Client<S = TcpStream> {
+    messages: Framed<S, MessagesCodec>,
+    next_request_id: u32,
+}
+
+/// Message received from the server after the handshake
+#[derive(Debug)]
+pub enum Event {
+    NearbyClients(InformNearbyClients),
+    ChatMessage(OutgoingChatMessage),
+    /// Chat message sent before joining, only received right after the handshake
+    ChatHistory(OutgoingChatMessage),
+    StatusUpdate(ServerStatusUpdate),
+    PlayerRenamed(PlayerRenamed),
+    /// Replaces the token to resume the session with
+    SessionToken(SessionToken),
+    Ack(Ack),
+    RequestError(RequestError),
+    Notice(ServerNotice),
+    /// Message that the server isn't expected to send after the handshake
+    Other(Message),
+}
+
+impl From<Message> for Event {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::InformNearbyClients(message) => Event::NearbyClients(message),
+            Message::OutgoingChatMessage(message) => Event::ChatMessage(message),
+            Message::ChatHistory(message) => Event::ChatHistory(message.message),
+            Message::ServerStatusUpdate(message) => Event::StatusUpdate(message),
+            Message::PlayerRenamed(message) => Event::PlayerRenamed(message),
+            Message::SessionToken(message) => Event::SessionToken(message),
+            Message::Ack(message) => Event::Ack(message),
+            Message::RequestError(message) => Event::RequestError(message),
+            Message::ServerNotice(message) => Event::Notice(message),
+            message => Event::Other(message),
+        }
+    }
+}
+
+/// Returned by the handshake when the server rejected it
+#[derive(Debug)]
+pub struct HandshakeRejected {
+    pub error: Option<HandshakeError>,
+    pub error_message: Option<String>,
+    /// Seconds after which connecting again may succeed
+    pub retry_after: Option<u32>,
+}
+
+impl Display for HandshakeRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error_message {
+            Some(error_message) => write!(f, "Handshake rejected: {}", error_message),
+            None => write!(f, "Handshake rejected"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeRejected {}
+
+impl Client<TcpStream> {
+    /// Connects to the server with the default codec settings
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> Result<Self, anyhow::Error> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream, MessagesCodec::default()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Wraps an established connection, for example a TLS stream
+    pub fn new(stream: S, codec: MessagesCodec) -> Self {
+        Self {
+            messages: codec.framed(stream),
+            next_request_id: 0,
+        }
+    }
+
+    /// Protocol version of the connection, only meaningful after the handshake
+    pub fn version(&self) -> u32 {
+        self.messages.codec().version()
+    }
+
+    /// Compression of large frames the server selected in the handshake
+    pub fn compression(&self) -> Option<CompressionAlgorithm> {
+        self.messages.codec().compression()
+    }
+
+    /// Authenticates with the server. Waits while the server is full and the player is queued.
+    /// Returns the negotiated protocol version, or a `HandshakeRejected` error if the server rejected the handshake.
+    pub async fn handshake(&mut self, request: HandshakeRequest) -> Result<u32, anyhow::Error> {
+        let version = request.version;
+        self.send(Message::HandshakeRequest(request)).await?;
+        self.finish_handshake(version).await
+    }
+
+    /// Takes over a previous session with the token from the last `SessionToken` received.
+    /// Returns the negotiated protocol version, or a `HandshakeRejected` error if the session can't be resumed.
+    pub async fn resume_session(&mut self, token: Vec<u8>) -> Result<u32, anyhow::Error> {
+        self.send(Message::ResumeSession(ResumeSession {
+            token,
+            version: crate::VERSION,
+            compression: codec::SUPPORTED_COMPRESSION.to_vec(),
+        }))
+        .await?;
+        self.finish_handshake(crate::VERSION).await
+    }
+
+    async fn finish_handshake(&mut self, requested_version: u32) -> Result<u32, anyhow::Error> {
+        let mut retry_after = None;
+
+        loop {
+            match self.receive().await? {
+                Message::HandshakeResponse(response) if response.success => break,
+                Message::HandshakeResponse(response) => {
+                    return Err(HandshakeRejected {
+                        error: response.error,
+                        error_message: response.error_message,
+                        retry_after,
+                    }
+                    .into());
+                }
+                Message::QueuePosition(queue_position) => {
+                    tracing::debug!(
+                        "Waiting for admission at position {}",
+                        queue_position.position
+                    );
+                }
+                Message::RetryAfter(message) => retry_after = Some(message.seconds),
+                message => anyhow::bail!("Unexpected {} during handshake", message.name()),
+            }
+        }
+
+        // The negotiated version isn't announced to clients that don't know `ProtocolNegotiated`
+        if requested_version < messages::NEGOTIATION_VERSION {
+            self.messages.codec_mut().set_version(requested_version);
+            return Ok(requested_version);
+        }
+
+        let (version, compression) = match self.receive().await? {
+            Message::ProtocolNegotiated(message) => (message.version, message.compression),
+            message => anyhow::bail!("Expected ProtocolNegotiated, received {}", message.name()),
+        };
+
+        let codec = self.messages.codec_mut();
+        codec.set_version(version);
+
+        if version >= codec::FRAME_FLAGS_VERSION {
+            codec.enable_frame_flags();
+            codec.set_compression(compression);
+        }
+
+        Ok(version)
+    }
+
+    async fn receive(&mut self) -> Result<Message, anyhow::Error> {
+        match self.messages.next().await {
+            Some(message) => message,
+            None => anyhow::bail!("Connection closed by the server"),
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), anyhow::Error> {
+        self.messages.send(message).await
+    }
+
+    /// Sends the message as a request, returns the id of the `Ack` or `RequestError` the server replies with
+    pub async fn send_request(&mut self, message: RequestMessage) -> Result<u32, anyhow::Error> {
+        let id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        self.send(Message::Request(Request { id, message })).await?;
+
+        Ok(id)
+    }
+
+    pub async fn send_position(&mut self, position: Vector2) -> Result<(), anyhow::Error> {
+        self.send(Message::PositionUpdate(PositionUpdate { position }))
+            .await
+    }
+
+    pub async fn send_chat_message(
+        &mut self,
+        channel: ChatChannel,
+        message: String,
+    ) -> Result<(), anyhow::Error> {
+        self.send(Message::IncomingChatMessage(IncomingChatMessage {
+            channel,
+            message,
+        }))
+        .await
+    }
+
+    pub async fn set_matchmaking_password(
+        &mut self,
+        password: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        self.send(Message::SetMatchmakingPassword(SetMatchmakingPassword {
+            password,
+        }))
+        .await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for Client<S> {
+    type Item = Result<Event, anyhow::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let message = match futures::ready!(self.messages.poll_next_unpin(cx)) {
+            Some(Ok(message)) => message,
+            Some(Err(error)) => return Poll::Ready(Some(Err(error))),
+            None => return Poll::Ready(None),
+        };
+
+        Poll::Ready(Some(Ok(message.into())))
+    }
+}
@@ -0,0 +1,498 @@
+use bincode::{
+    config::{
+        Bounded, LittleEndian, VarintEncoding, WithOtherEndian, WithOtherIntEncoding,
+        WithOtherLimit,
+    },
+    DefaultOptions, Options,
+};
+use std::io::{Read, Write};
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::messages::{CompressionAlgorithm, Message};
+
+/// Default maximum size in bytes of a single serialized message, matches the server default
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 4096;
+/// Default size in bytes from which frames are compressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Protocol version that introduced frame flags and compression
+pub const FRAME_FLAGS_VERSION: u32 = 11;
+
+/// Compression algorithms the codec implements, in order of preference
+pub const SUPPORTED_COMPRESSION: [CompressionAlgorithm; 1] = [CompressionAlgorithm::Deflate];
+
+/// Set in the length prefix of frames with a compressed payload once frame flags are enabled
+const FLAG_COMPRESSED: u64 = 1;
+const FLAG_BITS: u32 = 1;
+
+/// Sent as the first byte by clients that want to use json frames.
+/// It can't be mistaken for the start of a bincode frame since it's not a valid length prefix.
+pub const JSON_MAGIC_BYTE: u8 = 0xff;
+
+/// Serialization format of the message payloads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Bincode,
+    /// Human readable frames for debugging, these never use frame flags
+    Json,
+}
+
+pub struct MessagesCodec {
+    options: WithOtherIntEncoding<
+        WithOtherLimit<WithOtherEndian<DefaultOptions, LittleEndian>, Bounded>,
+        VarintEncoding,
+    >,
+    format: Format,
+    version: u32,
+    /// When enabled the lowest bits of the length prefix carry the frame flags
+    frame_flags: bool,
+    compression: Option<CompressionAlgorithm>,
+    /// Payloads smaller than this are never compressed
+    compression_threshold: usize,
+    max_message_size: u64,
+}
+
+impl MessagesCodec {
+    pub fn new(max_message_size: u64, compression_threshold: usize) -> Self {
+        Self {
+            options: DefaultOptions::new()
+                .with_little_endian()
+                .with_limit(max_message_size)
+                .with_varint_encoding(),
+            format: Format::Bincode,
+            version: crate::VERSION,
+            frame_flags: false,
+            compression: None,
+            compression_threshold,
+            max_message_size,
+        }
+    }
+
+    /// Enables frame flags in the length prefix of all following frames in both directions
+    pub fn enable_frame_flags(&mut self) {
+        self.frame_flags = self.format == Format::Bincode;
+    }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    fn serialize(&self, message: &Message) -> Result<Vec<u8>, anyhow::Error> {
+        match self.format {
+            Format::Bincode => Ok(self.options.serialize(message)?),
+            Format::Json => Ok(serde_json::to_vec(message)?),
+        }
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> Result<Message, anyhow::Error> {
+        match self.format {
+            Format::Bincode => Ok(self.options.deserialize(payload)?),
+            Format::Json => {
+                // The bincode options enforce the size limit for bincode payloads
+                if payload.len() as u64 > self.max_message_size {
+                    anyhow::bail!("Message is too large");
+                }
+
+                Ok(serde_json::from_slice(payload)?)
+            }
+        }
+    }
+
+    /// Sets the algorithm used to compress large payloads of following frames, requires frame flags
+    pub fn set_compression(&mut self, compression: Option<CompressionAlgorithm>) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Option<CompressionAlgorithm> {
+        self.compression
+    }
+
+    fn compress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < self.compression_threshold {
+            return None;
+        }
+
+        let compressed = match self.compression? {
+            CompressionAlgorithm::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(payload).ok()?;
+                encoder.finish().ok()?
+            }
+        };
+
+        // Incompressible payloads are sent as is
+        match compressed.len() < payload.len() {
+            true => Some(compressed),
+            false => None,
+        }
+    }
+
+    fn decompress(&self, payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut decompressed = Vec::new();
+
+        match self.compression {
+            Some(CompressionAlgorithm::Deflate) => {
+                // Read one byte more than allowed to detect payloads that are too large
+                DeflateDecoder::new(payload)
+                    .take(self.max_message_size + 1)
+                    .read_to_end(&mut decompressed)?;
+            }
+            None => anyhow::bail!("Received compressed frame without negotiating compression"),
+        }
+
+        if decompressed.len() as u64 > self.max_message_size {
+            anyhow::bail!("Decompressed message is too large");
+        }
+
+        Ok(decompressed)
+    }
+
+    /// Sets the negotiated protocol version. Messages that are newer than the version are not sent
+    /// and are rejected when received.
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl Default for MessagesCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_COMPRESSION_THRESHOLD)
+    }
+}
+
+impl Encoder<Message> for MessagesCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.min_version() > self.version {
+            tracing::trace!(
+                "Not sending {} to client with protocol version {}",
+                item.name(),
+                self.version
+            );
+            return Ok(());
+        }
+
+        let payload = self.serialize(&item)?;
+
+        if !self.frame_flags {
+            crate::encoding::put_varint_le(dst, payload.len() as u64);
+            dst.put_slice(&payload);
+            return Ok(());
+        }
+
+        match self.compress(&payload) {
+            Some(compressed) => {
+                let prefix = ((compressed.len() as u64) << FLAG_BITS) | FLAG_COMPRESSED;
+                crate::encoding::put_varint_le(dst, prefix);
+                dst.put_slice(&compressed);
+            }
+            None => {
+                crate::encoding::put_varint_le(dst, (payload.len() as u64) << FLAG_BITS);
+                dst.put_slice(&payload);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Decoder for MessagesCodec {
+    type Item = Message;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (prefix, prefix_length) = match crate::encoding::peek_varint_le(src)? {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+        let (length, compressed) = match self.frame_flags {
+            true => (
+                (prefix >> FLAG_BITS) as usize,
+                prefix & FLAG_COMPRESSED != 0,
+            ),
+            false => (prefix as usize, false),
+        };
+
+        if length as u64 > self.max_message_size {
+            anyhow::bail!("Message length ({}) is too large", length);
+        }
+
+        if length == 0 {
+            anyhow::bail!("Message length is zero");
+        }
+
+        // Wait until the whole frame has been received
+        if src.len() < prefix_length + length {
+            src.reserve(prefix_length + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(prefix_length);
+
+        let message = match compressed {
+            true => self.deserialize(&self.decompress(&src[..length])?)?,
+            false => self.deserialize(&src[..length])?,
+        };
+        src.advance(length);
+
+        if message.min_version() > self.version {
+            anyhow::bail!(
+                "{} is not supported by protocol version {}",
+                message.name(),
+                self.version
+            );
+        }
+
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        math::Vector2,
+        messages::{
+            Ack, NoticeCode, NoticeSeverity, OutgoingChatMessage, PositionUpdate, Request,
+            RequestMessage, ServerNotice,
+        },
+    };
+
+    const THRESHOLD: usize = 64;
+
+    fn codec(compression: Option<CompressionAlgorithm>) -> MessagesCodec {
+        let mut codec = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE, THRESHOLD);
+        codec.enable_frame_flags();
+        codec.set_compression(compression);
+        codec
+    }
+
+    fn chat_message(message: &str) -> Message {
+        Message::OutgoingChatMessage(OutgoingChatMessage::system(message.to_string()))
+    }
+
+    fn encode(codec: &mut MessagesCodec, message: Message) -> BytesMut {
+        let mut frame = BytesMut::new();
+        codec.encode(message, &mut frame).unwrap();
+        frame
+    }
+
+    /// Returns the length and whether the compressed flag is set
+    fn read_prefix(frame: &[u8]) -> (usize, bool) {
+        let (prefix, _) = crate::encoding::peek_varint_le(frame).unwrap().unwrap();
+        (
+            (prefix >> FLAG_BITS) as usize,
+            prefix & FLAG_COMPRESSED != 0,
+        )
+    }
+
+    fn decode_message(codec: &mut MessagesCodec, frame: &mut BytesMut) -> String {
+        match codec.decode(frame).unwrap() {
+            Some(Message::OutgoingChatMessage(message)) => message.message,
+            message => panic!("Unexpected {:?}", message),
+        }
+    }
+
+    #[test]
+    fn round_trips_frames_without_flags() {
+        let mut codec = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE, THRESHOLD);
+        let text = "a".repeat(THRESHOLD * 4);
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        // Without frame flags the prefix is the plain length
+        let (prefix, prefix_length) = crate::encoding::peek_varint_le(&frame).unwrap().unwrap();
+        assert_eq!(prefix as usize, frame.len() - prefix_length);
+
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn round_trips_uncompressed_frames() {
+        let mut codec = codec(None);
+        let text = "a".repeat(THRESHOLD * 4);
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        let (length, compressed) = read_prefix(&frame);
+        assert!(!compressed);
+        assert!(length > THRESHOLD * 4);
+
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+    }
+
+    #[test]
+    fn round_trips_compressed_frames() {
+        let mut codec = codec(Some(CompressionAlgorithm::Deflate));
+        let text = "a".repeat(THRESHOLD * 4);
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        let (length, compressed) = read_prefix(&frame);
+        assert!(compressed);
+        assert!(length < THRESHOLD);
+
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+    }
+
+    #[test]
+    fn does_not_compress_frames_below_threshold() {
+        let mut codec = codec(Some(CompressionAlgorithm::Deflate));
+        let text = "a".repeat(THRESHOLD / 2);
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        assert!(!read_prefix(&frame).1);
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+    }
+
+    #[test]
+    fn does_not_compress_incompressible_frames() {
+        let mut codec = codec(Some(CompressionAlgorithm::Deflate));
+        // Pseudo random printable characters that deflate can't shrink
+        let mut seed: u32 = 1;
+        let text: String = (0..THRESHOLD * 2)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'!' + (seed >> 16) as u8 % 90)
+            })
+            .collect();
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        assert!(!read_prefix(&frame).1);
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+    }
+
+    #[test]
+    fn rejects_compressed_frames_without_compression() {
+        let text = "a".repeat(THRESHOLD * 4);
+        let mut frame = encode(
+            &mut codec(Some(CompressionAlgorithm::Deflate)),
+            chat_message(&text),
+        );
+
+        assert!(codec(None).decode(&mut frame).is_err());
+    }
+
+    #[test]
+    fn rejects_frames_that_decompress_beyond_the_limit() {
+        let text = "a".repeat(DEFAULT_MAX_MESSAGE_SIZE as usize * 2);
+        let mut encoder = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE * 4, THRESHOLD);
+        encoder.enable_frame_flags();
+        encoder.set_compression(Some(CompressionAlgorithm::Deflate));
+        let mut frame = encode(&mut encoder, chat_message(&text));
+
+        assert!(codec(Some(CompressionAlgorithm::Deflate))
+            .decode(&mut frame)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_nested_requests() {
+        let request = Message::Request(Request {
+            id: 0,
+            message: RequestMessage::PositionUpdate(PositionUpdate {
+                position: Vector2 { x: 0.0, y: 0.0 },
+            }),
+        });
+        let request = DefaultOptions::new().serialize(&request).unwrap();
+        assert_eq!(request[..2], [15, 0]);
+
+        // Each `[15, 0]` starts a request with id 0, nesting them used to overflow the stack while decoding
+        let mut payload = [15, 0].repeat(2000);
+        payload.extend_from_slice(&request);
+        let mut codec = MessagesCodec::new(payload.len() as u64, THRESHOLD);
+        let mut frame = BytesMut::new();
+        crate::encoding::put_varint_le(&mut frame, payload.len() as u64);
+        frame.put_slice(&payload);
+
+        assert!(codec.decode(&mut frame).is_err());
+    }
+
+    #[test]
+    fn round_trips_json_frames() {
+        let mut codec = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE, THRESHOLD);
+        codec.set_format(Format::Json);
+        let mut frame = encode(&mut codec, chat_message("Hello"));
+
+        let (length, prefix_length) = crate::encoding::peek_varint_le(&frame).unwrap().unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&frame[prefix_length..]).unwrap();
+        assert_eq!(length as usize, frame.len() - prefix_length);
+        assert_eq!(payload["OutgoingChatMessage"]["message"], "Hello");
+
+        assert_eq!(decode_message(&mut codec, &mut frame), "Hello");
+    }
+
+    #[test]
+    fn json_frames_never_use_flags() {
+        let mut codec = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE, THRESHOLD);
+        codec.set_format(Format::Json);
+        codec.enable_frame_flags();
+        codec.set_compression(Some(CompressionAlgorithm::Deflate));
+
+        let text = "a".repeat(THRESHOLD * 4);
+        let mut frame = encode(&mut codec, chat_message(&text));
+
+        let (length, prefix_length) = crate::encoding::peek_varint_le(&frame).unwrap().unwrap();
+        assert_eq!(length as usize, frame.len() - prefix_length);
+        assert_eq!(decode_message(&mut codec, &mut frame), text);
+    }
+
+    #[test]
+    fn rejects_json_frames_above_the_limit() {
+        let text = "a".repeat(DEFAULT_MAX_MESSAGE_SIZE as usize);
+        let mut encoder = MessagesCodec::new(DEFAULT_MAX_MESSAGE_SIZE * 2, THRESHOLD);
+        encoder.set_format(Format::Json);
+        let mut frame = encode(&mut encoder, chat_message(&text));
+
+        let mut decoder = MessagesCodec::default();
+        decoder.set_format(Format::Json);
+        assert!(decoder.decode(&mut frame).is_err());
+    }
+
+    #[test]
+    fn skips_messages_newer_than_the_version() {
+        let mut codec = MessagesCodec::default();
+        codec.set_version(crate::MIN_SUPPORTED_VERSION);
+
+        let frame = encode(
+            &mut codec,
+            Message::ServerNotice(ServerNotice::new(
+                NoticeSeverity::Warning,
+                NoticeCode::ShuttingDown,
+            )),
+        );
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn rejects_messages_newer_than_the_version() {
+        let mut frame = encode(&mut MessagesCodec::default(), Message::Ack(Ack { id: 1 }));
+        let mut codec = MessagesCodec::default();
+        codec.set_version(crate::MIN_SUPPORTED_VERSION);
+
+        assert!(codec.decode(&mut frame).is_err());
+    }
+
+    #[test]
+    fn waits_for_complete_frames() {
+        let mut codec = codec(Some(CompressionAlgorithm::Deflate));
+        let text = "a".repeat(THRESHOLD * 4);
+        let frame = encode(&mut codec, chat_message(&text));
+        let mut partial = BytesMut::from(&frame[..frame.len() - 1]);
+
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+
+        partial.put_u8(frame[frame.len() - 1]);
+        assert_eq!(decode_message(&mut codec, &mut partial), text);
+    }
+}
@@ -8,20 +8,44 @@ const U16_BYTE: u8 = 251;
 const U32_BYTE: u8 = 252;
 const U64_BYTE: u8 = 253;
 
-pub fn get_varint_le(src: &mut BytesMut) -> Result<u64, anyhow::Error> {
+pub fn get_varint_le<B: Buf>(src: &mut B) -> Result<u64, anyhow::Error> {
     let discriminant = src.get_u8();
 
     let out = match discriminant {
         byte @ 0..=SINGLE_BYTE_MAX => byte as u64,
         U16_BYTE => src.get_u16_le() as u64,
         U32_BYTE => src.get_u32_le() as u64,
-        U64_BYTE => src.get_u64_le() as u64,
+        U64_BYTE => src.get_u64_le(),
         _ => anyhow::bail!("Invalid discriminant = {}", discriminant),
     };
 
     Ok(out)
 }
 
+/// Reads a varint from the start of the buffer without consuming it.
+/// Returns the value and the amount of bytes it takes up, or None if the buffer doesn't contain all of it yet.
+pub fn peek_varint_le(src: &[u8]) -> Result<Option<(u64, usize)>, anyhow::Error> {
+    let discriminant = match src.first() {
+        Some(discriminant) => *discriminant,
+        None => return Ok(None),
+    };
+
+    let length = match discriminant {
+        0..=SINGLE_BYTE_MAX => 1,
+        U16_BYTE => 3,
+        U32_BYTE => 5,
+        U64_BYTE => 9,
+        _ => anyhow::bail!("Invalid discriminant = {}", discriminant),
+    };
+
+    if src.len() < length {
+        return Ok(None);
+    }
+
+    let value = get_varint_le(&mut &src[..length])?;
+    Ok(Some((value, length)))
+}
+
 pub fn put_varint_le(src: &mut BytesMut, val: u64) {
     if val <= SINGLE_BYTE_MAX as u64 {
         src.put_u8(val as u8);
@@ -0,0 +1,16 @@
+//! Protocol types and an async client for the JKMP matchmaking server.
+//! The server is built on the same types, so clients using this crate always speak its protocol.
+
+pub mod chat;
+pub mod codec;
+pub mod encoding;
+pub mod math;
+pub mod messages;
+
+mod client;
+pub use client::{Client, Event, HandshakeRejected};
+
+/// Highest supported protocol version
+pub const VERSION: u32 = 13;
+/// Lowest protocol version supported by the server
+pub const MIN_SUPPORTED_VERSION: u32 = 3;
@@ -0,0 +1,694 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{chat::ChatChannel, math::Vector2};
+
+/// Protocol version that introduced `ChatHistory`
+pub const HISTORY_VERSION: u32 = 4;
+/// Protocol version that introduced `PlayerRenamed`
+pub const RENAME_VERSION: u32 = 5;
+/// Protocol version that introduced `HandshakeResponse::error`
+pub const HANDSHAKE_ERROR_VERSION: u32 = 6;
+/// Protocol version that introduced `ProtocolNegotiated`
+pub const NEGOTIATION_VERSION: u32 = 7;
+/// Protocol version that introduced session resumption
+pub const RESUME_SESSION_VERSION: u32 = 8;
+/// Protocol version that introduced `QueuePosition` and `RetryAfter`
+pub const ADMISSION_VERSION: u32 = 9;
+/// Protocol version that introduced `HandshakeError::RateLimited`
+pub const RATE_LIMIT_VERSION: u32 = 10;
+/// Protocol version that introduced request ids with `Ack` and `RequestError`
+pub const REQUEST_VERSION: u32 = 12;
+/// Protocol version that introduced `ServerNotice`
+pub const NOTICE_VERSION: u32 = 13;
+
+// Allows incoming and outgoing chat message variants to end in "Message"
+// without warning us about enum variants being suffixed by the same name as the enum
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    HandshakeRequest(HandshakeRequest),
+    HandshakeResponse(HandshakeResponse),
+    PositionUpdate(PositionUpdate),
+    SetMatchmakingPassword(SetMatchmakingPassword),
+    InformNearbyClients(InformNearbyClients),
+    IncomingChatMessage(IncomingChatMessage),
+    OutgoingChatMessage(OutgoingChatMessage),
+    ServerStatusUpdate(ServerStatusUpdate),
+    ChatHistory(ChatHistory),
+    PlayerRenamed(PlayerRenamed),
+    ProtocolNegotiated(ProtocolNegotiated),
+    ResumeSession(ResumeSession),
+    SessionToken(SessionToken),
+    QueuePosition(QueuePosition),
+    RetryAfter(RetryAfter),
+    Request(Request),
+    Ack(Ack),
+    RequestError(RequestError),
+    ServerNotice(ServerNotice),
+}
+
+impl Message {
+    /// Name of the variant, used as a metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::HandshakeRequest(_) => "HandshakeRequest",
+            Message::HandshakeResponse(_) => "HandshakeResponse",
+            Message::PositionUpdate(_) => "PositionUpdate",
+            Message::SetMatchmakingPassword(_) => "SetMatchmakingPassword",
+            Message::InformNearbyClients(_) => "InformNearbyClients",
+            Message::IncomingChatMessage(_) => "IncomingChatMessage",
+            Message::OutgoingChatMessage(_) => "OutgoingChatMessage",
+            Message::ServerStatusUpdate(_) => "ServerStatusUpdate",
+            Message::ChatHistory(_) => "ChatHistory",
+            Message::PlayerRenamed(_) => "PlayerRenamed",
+            Message::ProtocolNegotiated(_) => "ProtocolNegotiated",
+            Message::ResumeSession(_) => "ResumeSession",
+            Message::SessionToken(_) => "SessionToken",
+            Message::QueuePosition(_) => "QueuePosition",
+            Message::RetryAfter(_) => "RetryAfter",
+            Message::Request(_) => "Request",
+            Message::Ack(_) => "Ack",
+            Message::RequestError(_) => "RequestError",
+            Message::ServerNotice(_) => "ServerNotice",
+        }
+    }
+
+    /// Lowest protocol version the variant can be sent with
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Message::HandshakeRequest(_)
+            | Message::HandshakeResponse(_)
+            | Message::PositionUpdate(_)
+            | Message::SetMatchmakingPassword(_)
+            | Message::InformNearbyClients(_)
+            | Message::IncomingChatMessage(_)
+            | Message::OutgoingChatMessage(_)
+            | Message::ServerStatusUpdate(_) => crate::MIN_SUPPORTED_VERSION,
+            Message::ChatHistory(_) => HISTORY_VERSION,
+            Message::PlayerRenamed(_) => RENAME_VERSION,
+            Message::ProtocolNegotiated(_) => NEGOTIATION_VERSION,
+            Message::ResumeSession(_) | Message::SessionToken(_) => RESUME_SESSION_VERSION,
+            Message::QueuePosition(_) | Message::RetryAfter(_) => ADMISSION_VERSION,
+            Message::Request(_) | Message::Ack(_) | Message::RequestError(_) => REQUEST_VERSION,
+            Message::ServerNotice(_) => NOTICE_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    pub auth_session_ticket: Vec<u8>,
+    pub matchmaking_password: Option<String>,
+    pub level_name: String,
+    pub position: Vector2,
+    /// Highest protocol version supported by the client
+    pub version: u32,
+    /// Compression algorithms the client accepts, in order of preference. Ignored before protocol version 11.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_appended_field"
+    )]
+    pub compression: Vec<CompressionAlgorithm>,
+}
+
+/// The encoding of the response must not change between protocol versions since it's sent before
+/// the client knows which version was negotiated, so fields are only added for clients that requested a version that has them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    pub success: bool,
+    /// English description of the error
+    pub error_message: Option<String>,
+    /// Reason the handshake was rejected. Not sent before protocol version 6.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_appended_field"
+    )]
+    pub error: Option<HandshakeError>,
+}
+
+impl HandshakeResponse {
+    pub fn accepted() -> Self {
+        Self {
+            success: true,
+            error_message: None,
+            error: None,
+        }
+    }
+
+    pub fn rejected(error: HandshakeError) -> Self {
+        Self {
+            success: false,
+            error_message: Some(error.description().to_string()),
+            error: Some(error),
+        }
+    }
+
+    /// Leaves out the fields clients of the given protocol version can't decode
+    pub fn for_version(self, version: u32) -> Self {
+        match version >= HANDSHAKE_ERROR_VERSION {
+            true => self,
+            false => Self {
+                error: None,
+                ..self
+            },
+        }
+    }
+}
+
+/// Reason a handshake was rejected. New variants must be added at the end to keep the encoding stable.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum HandshakeError {
+    /// The client uses an older protocol version than the server supports
+    ClientOutdated,
+    /// The client uses a newer protocol version than the server supports.
+    /// No longer sent since protocol version 7 since newer clients negotiate a lower version instead.
+    ServerOutdated,
+    /// The auth session ticket could not be verified
+    AuthFailed,
+    Banned,
+    ServerFull,
+    Maintenance,
+    SteamUnavailable,
+    InternalError,
+    /// The resume token is invalid or the session has expired, a new handshake is required
+    InvalidSession,
+    /// Too many handshakes from the same address or too many players connecting at once
+    RateLimited,
+}
+
+impl HandshakeError {
+    /// Name of the variant, used as a metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            HandshakeError::ClientOutdated => "client_outdated",
+            HandshakeError::ServerOutdated => "server_outdated",
+            HandshakeError::AuthFailed => "auth_failed",
+            HandshakeError::Banned => "banned",
+            HandshakeError::ServerFull => "server_full",
+            HandshakeError::Maintenance => "maintenance",
+            HandshakeError::SteamUnavailable => "steam_unavailable",
+            HandshakeError::InternalError => "internal_error",
+            HandshakeError::InvalidSession => "invalid_session",
+            HandshakeError::RateLimited => "rate_limited",
+        }
+    }
+
+    /// Returns the closest error known to clients of the given protocol version
+    pub fn for_version(self, version: u32) -> Self {
+        match self {
+            HandshakeError::RateLimited if version < RATE_LIMIT_VERSION => {
+                HandshakeError::ServerFull
+            }
+            error => error,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            HandshakeError::ClientOutdated => "Your version is outdated",
+            HandshakeError::ServerOutdated => "The server is outdated, please try again later",
+            HandshakeError::AuthFailed => "Could not verify your steam account",
+            HandshakeError::Banned => "You are banned from this server",
+            HandshakeError::ServerFull => "The server is full, please try again later",
+            HandshakeError::Maintenance => {
+                "The server is under maintenance, please try again later"
+            }
+            HandshakeError::SteamUnavailable => {
+                "Steam is currently unavailable, please try again later"
+            }
+            HandshakeError::InternalError => {
+                "An unexpected error occured when handling handshake request"
+            }
+            HandshakeError::InvalidSession => "Your session has expired",
+            HandshakeError::RateLimited => {
+                "Too many players are connecting right now, please try again later"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PositionUpdate {
+    pub position: Vector2,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMatchmakingPassword {
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InformNearbyClients {
+    pub client_ids: Vec<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IncomingChatMessage {
+    pub channel: ChatChannel,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutgoingChatMessage {
+    pub channel: ChatChannel,
+    pub sender_id: Option<u64>,
+    pub sender_name: Option<String>,
+    pub message: String,
+}
+
+impl OutgoingChatMessage {
+    /// Creates a global message without a sender, shown to the player as coming from the server
+    pub fn system(message: String) -> Self {
+        Self {
+            channel: ChatChannel::Global,
+            sender_id: None,
+            sender_name: None,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerStatusUpdate {
+    pub total_players: u32,
+    pub group_players: u32,
+}
+
+/// Chat message sent before it was joined, replayed after the handshake so the player has some context
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatHistory {
+    pub message: OutgoingChatMessage,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerRenamed {
+    pub steam_id: u64,
+    pub name: String,
+}
+
+/// Sent right after a successful `HandshakeResponse` with the protocol version used for the rest of the connection.
+/// From protocol version 11 on the length prefix of every frame after this message carries frame flags,
+/// so clients must not send anything else before receiving it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProtocolNegotiated {
+    pub version: u32,
+    /// Algorithm both sides compress large frames with after this message, selected from the ones the client accepts
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_appended_field"
+    )]
+    pub compression: Option<CompressionAlgorithm>,
+}
+
+/// Sent instead of a `HandshakeRequest` to take over a session after losing the connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeSession {
+    /// Token from the last `SessionToken` received
+    pub token: Vec<u8>,
+    /// Highest protocol version supported by the client
+    pub version: u32,
+    /// Compression algorithms the client accepts, in order of preference
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_appended_field"
+    )]
+    pub compression: Vec<CompressionAlgorithm>,
+}
+
+/// Sent after a successful handshake or resume with the token to resume the session with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionToken {
+    pub token: Vec<u8>,
+}
+
+/// Sent during the handshake while the server is full and the player is waiting for a free slot
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuePosition {
+    /// Position in the queue, starting at 1
+    pub position: u32,
+}
+
+/// Sent right before a `HandshakeResponse` that rejects the player when connecting again later may succeed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetryAfter {
+    pub seconds: u32,
+}
+
+/// Algorithms that frames can be compressed with. New variants must be added at the end to keep the encoding stable.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CompressionAlgorithm {
+    Deflate,
+}
+
+/// Envelope around a message from the client that the server replies to with an `Ack` or `RequestError` with the same id
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u32,
+    pub message: RequestMessage,
+}
+
+/// Messages that can be sent in a `Request`. Requests can't contain other requests,
+/// which keeps the nesting depth of incoming messages fixed before the client is authenticated.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RequestMessage {
+    PositionUpdate(PositionUpdate),
+    SetMatchmakingPassword(SetMatchmakingPassword),
+    IncomingChatMessage(IncomingChatMessage),
+}
+
+impl TryFrom<Message> for RequestMessage {
+    type Error = Message;
+
+    /// Returns the message back if it can't be sent as a request
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::PositionUpdate(message) => Ok(RequestMessage::PositionUpdate(message)),
+            Message::SetMatchmakingPassword(message) => {
+                Ok(RequestMessage::SetMatchmakingPassword(message))
+            }
+            Message::IncomingChatMessage(message) => {
+                Ok(RequestMessage::IncomingChatMessage(message))
+            }
+            message => Err(message),
+        }
+    }
+}
+
+/// Sent when the request with the id was handled successfully
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ack {
+    pub id: u32,
+}
+
+/// Sent when the request with the id failed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestError {
+    pub id: u32,
+    pub code: RequestErrorCode,
+}
+
+/// Reason a request failed. New variants must be added at the end to keep the encoding stable.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RequestErrorCode {
+    /// The message can't be sent by clients or can't be wrapped in a request
+    UnexpectedMessage,
+    /// Chat messages can't be sent to the channel
+    InvalidChannel,
+    InternalError,
+}
+
+impl RequestErrorCode {
+    pub fn description(&self) -> &'static str {
+        match self {
+            RequestErrorCode::UnexpectedMessage => "Unexpected message",
+            RequestErrorCode::InvalidChannel => "Unexpected channel",
+            RequestErrorCode::InternalError => "Internal error",
+        }
+    }
+}
+
+/// Informs the client about a problem or an upcoming disconnect
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerNotice {
+    pub severity: NoticeSeverity,
+    pub code: NoticeCode,
+    /// English description, may contain details like the reason of a kick
+    pub message: String,
+}
+
+impl ServerNotice {
+    pub fn new(severity: NoticeSeverity, code: NoticeCode) -> Self {
+        Self {
+            severity,
+            code,
+            message: code.description().to_string(),
+        }
+    }
+
+    /// Returns the notice as a message known to clients of the given protocol version,
+    /// older clients receive its description as a system chat message instead
+    pub fn for_version(self, version: u32) -> Message {
+        match version >= NOTICE_VERSION {
+            true => Message::ServerNotice(self),
+            false => Message::OutgoingChatMessage(OutgoingChatMessage::system(self.message)),
+        }
+    }
+}
+
+/// New variants must be added at the end to keep the encoding stable
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum NoticeSeverity {
+    Info,
+    /// Something went wrong but the connection stays open
+    Warning,
+    /// The connection is closed after the notice
+    Error,
+}
+
+/// New variants must be added at the end to keep the encoding stable
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum NoticeCode {
+    UnexpectedMessage,
+    InvalidChannel,
+    /// A message could not be decoded
+    InvalidMessage,
+    InternalError,
+    Kicked,
+    Banned,
+    LoggedInElsewhere,
+    ShuttingDown,
+}
+
+impl NoticeCode {
+    pub fn description(&self) -> &'static str {
+        match self {
+            NoticeCode::UnexpectedMessage => "Unexpected message",
+            NoticeCode::InvalidChannel => "Unexpected channel",
+            NoticeCode::InvalidMessage => "Received an invalid message",
+            NoticeCode::InternalError => "An unexpected error occured",
+            NoticeCode::Kicked => "You have been kicked from the server",
+            NoticeCode::Banned => "You have been banned from the server",
+            NoticeCode::LoggedInElsewhere => {
+                "You have been disconnected because you logged in elsewhere"
+            }
+            NoticeCode::ShuttingDown => "The server is restarting",
+        }
+    }
+}
+
+impl From<RequestErrorCode> for NoticeCode {
+    fn from(code: RequestErrorCode) -> Self {
+        match code {
+            RequestErrorCode::UnexpectedMessage => NoticeCode::UnexpectedMessage,
+            RequestErrorCode::InvalidChannel => NoticeCode::InvalidChannel,
+            RequestErrorCode::InternalError => NoticeCode::InternalError,
+        }
+    }
+}
+
+/// Deserializes a field that was appended to a message which is also sent by older clients.
+/// Bincode has no field names, so the frames of older clients end before the field, which is read as the default value.
+/// The field must be the last one and skipped when serializing its default value.
+fn deserialize_appended_field<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(T::deserialize(deserializer).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new()
+    }
+
+    /// Layout of `HandshakeRequest` sent by clients before protocol version 11
+    #[derive(Serialize, Deserialize)]
+    struct OldHandshakeRequest {
+        auth_session_ticket: Vec<u8>,
+        matchmaking_password: Option<String>,
+        level_name: String,
+        position: Vector2,
+        version: u32,
+    }
+
+    /// Layout of `HandshakeResponse` sent to clients before protocol version 6
+    #[derive(Serialize, Deserialize)]
+    struct OldHandshakeResponse {
+        success: bool,
+        error_message: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct OldProtocolNegotiated {
+        version: u32,
+    }
+
+    fn old_handshake_request() -> OldHandshakeRequest {
+        OldHandshakeRequest {
+            auth_session_ticket: vec![1, 2, 3],
+            matchmaking_password: Some("secret".to_string()),
+            level_name: "Level".to_string(),
+            position: Vector2 { x: 1.0, y: 2.0 },
+            version: 10,
+        }
+    }
+
+    #[test]
+    fn decodes_handshake_request_of_old_clients() {
+        let bytes = options().serialize(&old_handshake_request()).unwrap();
+        let request: HandshakeRequest = options().deserialize(&bytes).unwrap();
+
+        assert_eq!(request.version, 10);
+        assert_eq!(request.level_name, "Level");
+        assert!(request.compression.is_empty());
+    }
+
+    #[test]
+    fn encodes_handshake_request_without_compression_like_old_clients() {
+        let request = HandshakeRequest {
+            auth_session_ticket: vec![1, 2, 3],
+            matchmaking_password: Some("secret".to_string()),
+            level_name: "Level".to_string(),
+            position: Vector2 { x: 1.0, y: 2.0 },
+            version: 10,
+            compression: Vec::new(),
+        };
+
+        assert_eq!(
+            options().serialize(&request).unwrap(),
+            options().serialize(&old_handshake_request()).unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trips_appended_fields() {
+        let negotiated = ProtocolNegotiated {
+            version: 11,
+            compression: Some(CompressionAlgorithm::Deflate),
+        };
+        let bytes = options().serialize(&negotiated).unwrap();
+        let decoded: ProtocolNegotiated = options().deserialize(&bytes).unwrap();
+        assert_eq!(decoded.compression, Some(CompressionAlgorithm::Deflate));
+
+        let resume = ResumeSession {
+            token: vec![1],
+            version: 11,
+            compression: vec![CompressionAlgorithm::Deflate],
+        };
+        let bytes = options().serialize(&resume).unwrap();
+        let decoded: ResumeSession = options().deserialize(&bytes).unwrap();
+        assert_eq!(decoded.compression, vec![CompressionAlgorithm::Deflate]);
+    }
+
+    #[test]
+    fn old_clients_can_decode_rejected_handshake_responses() {
+        let response = HandshakeResponse::rejected(HandshakeError::Banned).for_version(5);
+        let bytes = options().serialize(&response).unwrap();
+        let decoded: OldHandshakeResponse = options().deserialize(&bytes).unwrap();
+
+        assert!(!decoded.success);
+        assert_eq!(
+            decoded.error_message.as_deref(),
+            Some(HandshakeError::Banned.description())
+        );
+    }
+
+    #[test]
+    fn handshake_responses_carry_the_error() {
+        let response = HandshakeResponse::rejected(HandshakeError::Banned)
+            .for_version(HANDSHAKE_ERROR_VERSION);
+        let bytes = options().serialize(&response).unwrap();
+        let decoded: HandshakeResponse = options().deserialize(&bytes).unwrap();
+
+        assert_eq!(decoded.error, Some(HandshakeError::Banned));
+    }
+
+    #[test]
+    fn old_clients_can_decode_protocol_negotiated_without_compression() {
+        let negotiated = ProtocolNegotiated {
+            version: 10,
+            compression: None,
+        };
+        let bytes = options().serialize(&negotiated).unwrap();
+        let decoded: OldProtocolNegotiated = options().deserialize(&bytes).unwrap();
+
+        assert_eq!(decoded.version, 10);
+    }
+
+    #[test]
+    fn notices_fall_back_to_system_chat_messages() {
+        let notice = || ServerNotice::new(NoticeSeverity::Error, NoticeCode::Kicked);
+
+        match notice().for_version(NOTICE_VERSION) {
+            Message::ServerNotice(notice) => assert_eq!(notice.code, NoticeCode::Kicked),
+            message => panic!("Expected a notice, got {:?}", message),
+        }
+
+        match notice().for_version(NOTICE_VERSION - 1) {
+            Message::OutgoingChatMessage(message) => {
+                assert_eq!(message.sender_id, None);
+                assert_eq!(message.message, NoticeCode::Kicked.description());
+            }
+            message => panic!("Expected a chat message, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn request_errors_map_to_notices() {
+        assert_eq!(
+            NoticeCode::from(RequestErrorCode::UnexpectedMessage),
+            NoticeCode::UnexpectedMessage
+        );
+        assert_eq!(
+            NoticeCode::from(RequestErrorCode::InvalidChannel),
+            NoticeCode::InvalidChannel
+        );
+        assert_eq!(
+            NoticeCode::from(RequestErrorCode::InternalError),
+            NoticeCode::InternalError
+        );
+    }
+
+    #[test]
+    fn only_client_messages_can_be_requests() {
+        let position_update = Message::PositionUpdate(PositionUpdate {
+            position: Vector2 { x: 0.0, y: 0.0 },
+        });
+        assert!(matches!(
+            RequestMessage::try_from(position_update),
+            Ok(RequestMessage::PositionUpdate(_))
+        ));
+
+        let ack = Message::Ack(Ack { id: 1 });
+        assert!(matches!(
+            RequestMessage::try_from(ack),
+            Err(Message::Ack(_))
+        ));
+    }
+
+    #[test]
+    fn json_omits_default_appended_fields() {
+        let json = serde_json::to_string(&ProtocolNegotiated {
+            version: 10,
+            compression: None,
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"version":10}"#);
+
+        let decoded: ProtocolNegotiated = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.compression, None);
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    collections::{hash_map::Iter, HashMap},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ban {
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// List of banned steam ids, persisted as json
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct BanList {
+    bans: HashMap<u64, Ban>,
+}
+
+impl BanList {
+    /// Loads the ban list from the given path, or returns an empty list if the file doesn't exist
+    pub async fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match tokio::fs::read(path).await {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        tokio::fs::write(path, contents).await?;
+        Ok(())
+    }
+
+    pub fn is_banned(&self, steam_id: u64) -> bool {
+        self.bans.contains_key(&steam_id)
+    }
+
+    pub fn add(&mut self, steam_id: u64, ban: Ban) -> Option<Ban> {
+        self.bans.insert(steam_id, ban)
+    }
+
+    pub fn remove(&mut self, steam_id: u64) -> Option<Ban> {
+        self.bans.remove(&steam_id)
+    }
+
+    pub fn iter(&self) -> Iter<'_, u64, Ban> {
+        self.bans.iter()
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+
+use crate::messages::OutgoingChatMessage;
+
+/// Ring buffer of the most recent chat messages sent in a channel
+pub struct ChatHistory {
+    entries: VecDeque<ChatHistoryEntry>,
+}
+
+pub struct ChatHistoryEntry {
+    /// Used to restore the order of messages when merging the history of multiple channels
+    pub sequence: u64,
+    pub message: OutgoingChatMessage,
+}
+
+impl ChatHistory {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Adds a message, dropping the oldest messages if there are more than `capacity`
+    pub fn push(&mut self, sequence: u64, message: OutgoingChatMessage, capacity: usize) {
+        if capacity == 0 {
+            self.entries.clear();
+            return;
+        }
+
+        // The capacity can shrink when the config is reloaded, so more than one message may need to be dropped
+        while self.entries.len() >= capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries
+            .push_back(ChatHistoryEntry { sequence, message });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChatHistoryEntry> {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc,
+};
+
+use crate::chat::ChatChannel;
+
+const FILE_PREFIX: &str = "chat-";
+const FILE_EXTENSION: &str = "jsonl";
+
+pub struct ChatLogOptions {
+    pub directory: PathBuf,
+    /// Size in bytes after which a new file is started, even if the day hasn't changed
+    pub max_file_size: u64,
+    /// Log files that were last modified longer ago than this are deleted
+    pub retention: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub channel: ChatChannel,
+    /// Digest of the sender's matchmaking options, see `MatchmakingOptions::digest`
+    pub group: String,
+    pub sender_id: Option<u64>,
+    pub sender_name: Option<String>,
+    pub message: String,
+}
+
+/// Append-only log of chat messages written as json lines, rotated daily and by size
+pub struct ChatLog {
+    tx: mpsc::UnboundedSender<ChatLogEntry>,
+}
+
+impl ChatLog {
+    pub async fn open(options: ChatLogOptions) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(&options.directory).await?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = ChatLogWriter {
+            options,
+            file: None,
+            date: Utc::now().date_naive(),
+            index: 0,
+            size: 0,
+        };
+
+        tokio::spawn(writer.run(rx));
+
+        Ok(Self { tx })
+    }
+
+    pub fn write(&self, entry: ChatLogEntry) {
+        // The writer task only stops if the receiver is dropped, which doesn't happen while we hold the sender
+        let _ = self.tx.send(entry);
+    }
+}
+
+struct ChatLogWriter {
+    options: ChatLogOptions,
+    file: Option<File>,
+    date: NaiveDate,
+    index: u32,
+    size: u64,
+}
+
+impl ChatLogWriter {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<ChatLogEntry>) {
+        while let Some(entry) = rx.recv().await {
+            if let Err(error) = self.write(&entry).await {
+                tracing::error!("Failed to write chat log entry: {:?}", error);
+
+                // Reopen the file on the next write in case it was removed or became invalid
+                self.file = None;
+            }
+        }
+    }
+
+    async fn write(&mut self, entry: &ChatLogEntry) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        self.rotate_if_needed(entry.timestamp.date_naive()).await?;
+
+        let file = self.file.as_mut().unwrap();
+        file.write_all(&line).await?;
+        file.flush().await?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    async fn rotate_if_needed(&mut self, date: NaiveDate) -> Result<(), anyhow::Error> {
+        if self.file.is_some() && self.date == date && self.size < self.options.max_file_size {
+            return Ok(());
+        }
+
+        if self.date != date {
+            self.date = date;
+            self.index = 0;
+        }
+
+        // Skip past files that are already full, for example when the server was restarted
+        loop {
+            let path = self.get_file_path();
+            let size = match fs::metadata(&path).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+
+            if size < self.options.max_file_size {
+                self.file = Some(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&path)
+                        .await?,
+                );
+                self.size = size;
+                break;
+            }
+
+            self.index += 1;
+        }
+
+        if let Err(error) = self.remove_expired_files().await {
+            tracing::warn!("Failed to remove expired chat logs: {:?}", error);
+        }
+
+        Ok(())
+    }
+
+    fn get_file_path(&self) -> PathBuf {
+        let file_name = match self.index {
+            0 => format!("{}{}.{}", FILE_PREFIX, self.date, FILE_EXTENSION),
+            index => format!("{}{}.{}.{}", FILE_PREFIX, self.date, index, FILE_EXTENSION),
+        };
+
+        self.options.directory.join(file_name)
+    }
+
+    async fn remove_expired_files(&self) -> Result<(), anyhow::Error> {
+        let expiry_time = SystemTime::now() - self.options.retention;
+        let mut entries = fs::read_dir(&self.options.directory).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if !is_chat_log_file(&path) {
+                continue;
+            }
+
+            if entry.metadata().await?.modified()? < expiry_time {
+                tracing::info!("Removing expired chat log {}", path.display());
+                fs::remove_file(&path).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_chat_log_file(path: &Path) -> bool {
+    let file_name = path.file_name().and_then(|name| name.to_str());
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    matches!(file_name, Some(name) if name.starts_with(FILE_PREFIX))
+        && extension == Some(FILE_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, time::Duration};
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    /// Creates an empty directory that is unique to the test
+    fn temp_dir() -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("jkmp-chat-log-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn writer(directory: &Path, max_file_size: u64) -> ChatLogWriter {
+        ChatLogWriter {
+            options: ChatLogOptions {
+                directory: directory.to_path_buf(),
+                max_file_size,
+                retention: Duration::from_secs(24 * 60 * 60),
+            },
+            file: None,
+            date: Utc::now().date_naive(),
+            index: 0,
+            size: 0,
+        }
+    }
+
+    fn entry(timestamp: DateTime<Utc>) -> ChatLogEntry {
+        ChatLogEntry {
+            timestamp,
+            channel: ChatChannel::Global,
+            group: "0123456789abcdef".to_string(),
+            sender_id: Some(1),
+            sender_name: Some("Player".to_string()),
+            message: "Hello".to_string(),
+        }
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn rotates_when_file_is_full() {
+        let directory = temp_dir();
+        let timestamp = Utc.with_ymd_and_hms(2021, 8, 1, 12, 0, 0).unwrap();
+        // Every entry exceeds the size, so each one starts a new file
+        let mut writer = writer(&directory, 1);
+
+        for _ in 0..3 {
+            writer.write(&entry(timestamp)).await.unwrap();
+        }
+
+        assert_eq!(
+            file_names(&directory),
+            vec![
+                "chat-2021-08-01.1.jsonl",
+                "chat-2021-08-01.2.jsonl",
+                "chat-2021-08-01.jsonl"
+            ]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_when_day_changes() {
+        let directory = temp_dir();
+        let mut writer = writer(&directory, u64::MAX);
+
+        writer
+            .write(&entry(
+                Utc.with_ymd_and_hms(2021, 8, 1, 23, 59, 59).unwrap(),
+            ))
+            .await
+            .unwrap();
+        writer
+            .write(&entry(
+                Utc.with_ymd_and_hms(2021, 8, 1, 23, 59, 59).unwrap(),
+            ))
+            .await
+            .unwrap();
+        writer
+            .write(&entry(Utc.with_ymd_and_hms(2021, 8, 2, 0, 0, 0).unwrap()))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            file_names(&directory),
+            vec!["chat-2021-08-01.jsonl", "chat-2021-08-02.jsonl"]
+        );
+
+        let lines = fs::read_to_string(directory.join("chat-2021-08-01.jsonl")).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn continues_full_files_after_restart() {
+        let directory = temp_dir();
+        let timestamp = Utc.with_ymd_and_hms(2021, 8, 1, 12, 0, 0).unwrap();
+        fs::write(directory.join("chat-2021-08-01.jsonl"), vec![b'x'; 100]).unwrap();
+
+        writer(&directory, 100)
+            .write(&entry(timestamp))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            file_names(&directory),
+            vec!["chat-2021-08-01.1.jsonl", "chat-2021-08-01.jsonl"]
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_expired_chat_logs_only() {
+        let directory = temp_dir();
+        let expired_time = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+
+        for name in ["chat-2021-07-01.jsonl", "notes.txt"] {
+            let file = fs::File::create(directory.join(name)).unwrap();
+            file.set_modified(expired_time).unwrap();
+        }
+
+        writer(&directory, u64::MAX)
+            .write(&entry(Utc::now()))
+            .await
+            .unwrap();
+
+        let names = file_names(&directory);
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"notes.txt".to_string()));
+        assert!(!names.contains(&"chat-2021-07-01.jsonl".to_string()));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,8 +1,4 @@
-use serde::{Deserialize, Serialize};
+pub mod history;
+pub mod log;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
-pub enum ChatChannel {
-    Global,
-    Group,
-    Local,
-}
+pub use jkmp_client::chat::ChatChannel;
@@ -2,15 +2,42 @@ use std::fmt::Display;
 
 use tokio::sync::mpsc::{self, error::SendError};
 
-use crate::{math::Vector2, messages::Message, MessageType};
+use crate::{
+    math::Vector2,
+    messages::{Message, ServerNotice},
+    metrics,
+    session::ResumeToken,
+    MessageType,
+};
 
-pub const VERSION: u32 = 3;
+pub use jkmp_client::{MIN_SUPPORTED_VERSION, VERSION};
+
+/// Returns the highest protocol version supported by both the server and a client that supports up to `client_version`
+pub fn negotiate_version(client_version: u32) -> Option<u32> {
+    let version = client_version.min(VERSION);
+
+    match version >= MIN_SUPPORTED_VERSION {
+        true => Some(version),
+        false => None,
+    }
+}
+
+/// Items queued for the connection task of a client
+pub enum Outbound {
+    Message(Message),
+    /// Closes the connection after all previously queued messages have been sent
+    Disconnect,
+}
 
 pub struct Client {
     tx: mpsc::UnboundedSender<MessageType>,
     pub steam_id: u64,
     pub name: String,
     pub position: Vector2,
+    /// Negotiated protocol version of the connection
+    pub version: u32,
+    /// Token the client can resume its session with after losing the connection
+    pub resume_token: ResumeToken,
 }
 
 impl PartialEq for Client {
@@ -27,17 +54,47 @@ impl Client {
         steam_id: u64,
         name: String,
         position: Vector2,
+        version: u32,
     ) -> Self {
         Self {
             tx,
             steam_id,
             name,
             position,
+            version,
+            resume_token: ResumeToken::new(steam_id),
         }
     }
 
-    pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.tx.send(message)
+    /// Attaches the client to a new connection after resuming its session and issues a new resume token
+    pub fn reconnect(&mut self, tx: mpsc::UnboundedSender<MessageType>, version: u32) {
+        self.tx = tx;
+        self.version = version;
+        self.resume_token = ResumeToken::new(self.steam_id);
+    }
+
+    /// Returns true if messages to the client are sent through the channel
+    pub fn is_connected_through(&self, tx: &mpsc::UnboundedSender<MessageType>) -> bool {
+        self.tx.same_channel(tx)
+    }
+
+    pub fn send(&self, message: Message) -> Result<(), SendError<MessageType>> {
+        self.queue(Outbound::Message(message))
+    }
+
+    /// Sends the notice, or a system chat message with its description to clients that don't support notices
+    pub fn notify(&self, notice: ServerNotice) -> Result<(), SendError<MessageType>> {
+        self.send(notice.for_version(self.version))
+    }
+
+    pub fn disconnect(&self) -> Result<(), SendError<MessageType>> {
+        self.queue(Outbound::Disconnect)
+    }
+
+    fn queue(&self, outbound: Outbound) -> Result<(), SendError<MessageType>> {
+        self.tx.send(outbound)?;
+        metrics::OUTBOUND_QUEUE_DEPTH.inc();
+        Ok(())
     }
 }
 
@@ -1,41 +1,58 @@
-use bincode::{
-    config::{
-        Bounded, LittleEndian, VarintEncoding, WithOtherEndian, WithOtherIntEncoding,
-        WithOtherLimit,
-    },
-    DefaultOptions, Options,
-};
-use bytes::{Buf, BufMut, BytesMut};
+use std::ops::{Deref, DerefMut};
+
+use bytes::BytesMut;
+use jkmp_client::codec;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::messages::Message;
+use crate::{config, messages::Message, metrics};
+
+pub use jkmp_client::codec::{Format, FRAME_FLAGS_VERSION, JSON_MAGIC_BYTE, SUPPORTED_COMPRESSION};
 
+/// Protocol codec configured from the server config that records sent and received messages in the metrics
 pub struct MessagesCodec {
-    options: WithOtherIntEncoding<
-        WithOtherLimit<WithOtherEndian<DefaultOptions, LittleEndian>, Bounded>,
-        VarintEncoding,
-    >,
+    inner: codec::MessagesCodec,
 }
 
 impl MessagesCodec {
     pub fn new() -> Self {
+        let config = config::get();
+
         Self {
-            options: DefaultOptions::new()
-                .with_little_endian()
-                .with_limit(4096)
-                .with_varint_encoding(),
+            inner: codec::MessagesCodec::new(
+                config.protocol.max_message_size,
+                config.protocol.compression_threshold,
+            ),
         }
     }
 }
 
+impl Deref for MessagesCodec {
+    type Target = codec::MessagesCodec;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for MessagesCodec {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
 impl Encoder<Message> for MessagesCodec {
     type Error = anyhow::Error;
 
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let payload = self.options.serialize(&item)?;
+        let name = item.name();
+        // Messages that are too new for the client are skipped by the codec
+        let is_sent = item.min_version() <= self.inner.version();
+
+        self.inner.encode(item, dst)?;
 
-        crate::encoding::put_varint_le(dst, payload.len() as u64);
-        dst.put_slice(&payload);
+        if is_sent {
+            metrics::MESSAGES_SENT.with_label_values(&[name]).inc();
+        }
 
         Ok(())
     }
@@ -46,28 +63,14 @@ impl Decoder for MessagesCodec {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            return Ok(None);
-        }
+        let message = self.inner.decode(src)?;
 
-        let length = crate::encoding::get_varint_le(src)? as usize;
-        let remaining = src.remaining();
-
-        if length as usize > remaining {
-            anyhow::bail!(
-                "Message length ({}) > Remaining ({})",
-                length,
-                src.remaining()
-            );
-        }
-
-        if length == 0 {
-            anyhow::bail!("Message length is zero");
+        if let Some(message) = &message {
+            metrics::MESSAGES_RECEIVED
+                .with_label_values(&[message.name()])
+                .inc();
         }
 
-        let message: Self::Item = self.options.deserialize(&src[..length])?;
-        src.advance(length);
-
-        Ok(Some(message))
+        Ok(message)
     }
 }
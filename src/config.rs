@@ -0,0 +1,679 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_reflection::{ContainerFormat, Format, Registry, Tracer, TracerConfig};
+
+use crate::logging::LogFormat;
+
+/// Prefix of environment variables that override values from the config file.
+/// Nested keys are separated by two underscores, for example `JKMP_CHAT__MAX_MESSAGE_LENGTH`.
+const ENV_PREFIX: &str = "JKMP_";
+const ENV_SEPARATOR: &str = "__";
+
+lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+/// Returns the currently active configuration
+pub fn get() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+/// Replaces the active configuration, values that are only read on startup are not affected
+pub fn set(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config);
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Expect a PROXY protocol header at the start of every connection, used when running behind a load balancer
+    pub proxy_protocol: bool,
+    /// Seconds to wait for connected players to leave after receiving a shutdown signal
+    pub drain_timeout: u64,
+    /// File to load and save the ban list from
+    pub bans_file: Option<PathBuf>,
+    /// Cron schedule of the server status broadcast
+    pub status_broadcast_schedule: String,
+    /// Secret key of the group digests shown in logs and the admin api, a random key is used if not set
+    pub group_digest_key: Option<String>,
+    pub steam: SteamConfig,
+    pub protocol: ProtocolConfig,
+    pub chat: ChatConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub admission: AdmissionConfig,
+    pub limits: LimitsConfig,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub logging: LoggingConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SteamConfig {
+    pub app_id: u32,
+    /// Usually set through the `STEAM_API_KEY` environment variable
+    pub api_key: Option<String>,
+    /// Base url of the steam web api, only changed to test against a fake api
+    pub api_url: String,
+    /// Seconds to cache player summaries for
+    pub player_summary_ttl: u64,
+    /// Cron schedule for refreshing the names of connected players
+    pub name_refresh_schedule: String,
+    pub connect_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    /// Amount of times a request is retried after a server or network error
+    pub max_retries: u32,
+    /// Maximum delay before the first retry, doubled for every following retry
+    pub retry_base_delay_ms: u64,
+    /// Amount of consecutive failed requests after which requests are rejected without being sent
+    pub circuit_breaker_threshold: u32,
+    /// Seconds to reject requests for after the circuit breaker opens
+    pub circuit_breaker_cooldown: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolConfig {
+    /// Maximum size in bytes of a single serialized message, at most 65536
+    pub max_message_size: u64,
+    /// Seconds to keep the session of a client that lost its connection so it can resume it, 0 disables resuming
+    pub resume_grace_period: u64,
+    /// Allow clients to enable compression of large frames
+    pub compression: bool,
+    /// Size in bytes from which frames are compressed, applies to new connections
+    pub compression_threshold: usize,
+    /// Allow clients to use json frames by sending `codec::JSON_MAGIC_BYTE` first, meant for debugging
+    pub json_codec: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    /// Incoming chat messages are truncated to this amount of characters
+    pub max_message_length: usize,
+    /// Amount of messages to keep in the history of each chat channel
+    pub history_length: usize,
+    pub log: ChatLogConfig,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatLogConfig {
+    /// Directory to write chat logs to, chat logging is disabled if not set
+    pub directory: Option<PathBuf>,
+    /// Size in bytes after which the chat log is rotated
+    pub max_file_size: u64,
+    /// Amount of days to keep chat logs for
+    pub retention_days: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// Players within this amount of screens from eachother are matched with each other
+    pub proximity_screens: i32,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// Maximum amount of players that can be connected at once, unlimited if not set
+    pub max_players: Option<usize>,
+    /// Maximum amount of players waiting for a free slot
+    pub queue_length: usize,
+    /// Seconds after which players that were rejected because the server is full are told to try again
+    pub retry_after: u64,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Seconds a new connection has to send its handshake before it's closed, including the PROXY header and TLS handshake
+    pub handshake_timeout: u64,
+    pub max_connections_per_ip: usize,
+    /// Maximum amount of handshake attempts per ip address within a minute
+    pub max_handshakes_per_minute: u32,
+    /// Maximum amount of auth tickets being verified with steam at once, further handshakes are rejected
+    pub max_concurrent_verifications: usize,
+}
+
+#[derive(Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, TLS is enabled when both the certificate and key are set
+    pub cert_path: Option<PathBuf>,
+    /// PEM file with the private key
+    pub key_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Either "text" or "json"
+    pub format: LogFormat,
+    /// Filter directives to set the log level per module, for example "info,jkmp_backend_matchmaking::state=debug".
+    /// The RUST_LOG environment variable takes precedence if set.
+    pub filter: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// Url of the OTLP/HTTP trace endpoint, for example "http://localhost:4318/v1/traces". Trace export is disabled if not set.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    /// Fraction of traces to export, between 0 and 1
+    pub sample_ratio: f64,
+    /// Milliseconds to collect finished spans for before exporting them in a batch
+    pub export_delay_ms: u64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Port to serve the http api on, the http api is disabled if not set
+    pub port: Option<u16>,
+    /// Bearer token required to access the admin endpoints, usually set through the `ADMIN_TOKEN` environment variable
+    pub admin_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 16000,
+            proxy_protocol: false,
+            drain_timeout: 0,
+            bans_file: None,
+            status_broadcast_schedule: "1/60 * * * * *".to_string(),
+            group_digest_key: None,
+            steam: SteamConfig::default(),
+            protocol: ProtocolConfig::default(),
+            chat: ChatConfig::default(),
+            matchmaking: MatchmakingConfig::default(),
+            admission: AdmissionConfig::default(),
+            limits: LimitsConfig::default(),
+            tls: TlsConfig::default(),
+            http: HttpConfig::default(),
+            logging: LoggingConfig::default(),
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+            sample_ratio: 1.0,
+            export_delay_ms: 5000,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+impl Default for SteamConfig {
+    fn default() -> Self {
+        Self {
+            app_id: 1061090,
+            api_key: None,
+            api_url: "https://api.steampowered.com".to_string(),
+            player_summary_ttl: 600,
+            name_refresh_schedule: "0 */5 * * * *".to_string(),
+            connect_timeout_ms: 2000,
+            request_timeout_ms: 5000,
+            max_retries: 2,
+            retry_base_delay_ms: 200,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: 30,
+        }
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4096,
+            resume_grace_period: 30,
+            compression: true,
+            compression_threshold: 256,
+            json_codec: false,
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            max_message_length: 100,
+            history_length: 20,
+            log: ChatLogConfig::default(),
+        }
+    }
+}
+
+impl Default for ChatLogConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            max_file_size: 10 * 1024 * 1024,
+            retention_days: 30,
+        }
+    }
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        Self {
+            proximity_screens: 3,
+        }
+    }
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_players: None,
+            queue_length: 50,
+            retry_after: 30,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: 10,
+            max_connections_per_ip: 8,
+            max_handshakes_per_minute: 20,
+            max_concurrent_verifications: 32,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file if a path is given and applies overrides from environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let mut value = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Could not read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Could not parse config file {}", path.display()))?
+            }
+            None => toml::Value::Table(toml::value::Table::new()),
+        };
+
+        apply_env_overrides(&mut value, std::env::vars())?;
+
+        let mut config: Config = value.try_into().context("Invalid configuration")?;
+
+        if let Ok(api_key) = std::env::var("STEAM_API_KEY") {
+            config.steam.api_key = Some(api_key);
+        }
+
+        if let Ok(admin_token) = std::env::var("ADMIN_TOKEN") {
+            config.http.admin_token = Some(admin_token);
+        }
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.steam.api_key.is_none() {
+            anyhow::bail!("Steam api key is missing, set the STEAM_API_KEY environment variable");
+        }
+
+        cron::Schedule::from_str(&self.status_broadcast_schedule).map_err(|error| {
+            anyhow::anyhow!(
+                "status_broadcast_schedule is not a valid schedule: {}",
+                error
+            )
+        })?;
+
+        cron::Schedule::from_str(&self.steam.name_refresh_schedule).map_err(|error| {
+            anyhow::anyhow!(
+                "steam.name_refresh_schedule is not a valid schedule: {}",
+                error
+            )
+        })?;
+
+        // Every connection buffers up to this much before it's authenticated
+        if !(64..=65536).contains(&self.protocol.max_message_size) {
+            anyhow::bail!("protocol.max_message_size must be between 64 and 65536");
+        }
+
+        if self.chat.max_message_length == 0 {
+            anyhow::bail!("chat.max_message_length must be greater than 0");
+        }
+
+        if self.chat.log.max_file_size == 0 {
+            anyhow::bail!("chat.log.max_file_size must be greater than 0");
+        }
+
+        if self.matchmaking.proximity_screens < 0 {
+            anyhow::bail!("matchmaking.proximity_screens can not be negative");
+        }
+
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            anyhow::bail!("tls.cert_path and tls.key_path must be set together");
+        }
+
+        if self.admission.max_players == Some(0) {
+            anyhow::bail!("admission.max_players must be greater than 0");
+        }
+
+        if self.limits.handshake_timeout == 0 {
+            anyhow::bail!("limits.handshake_timeout must be greater than 0");
+        }
+
+        if self.limits.max_connections_per_ip == 0 {
+            anyhow::bail!("limits.max_connections_per_ip must be greater than 0");
+        }
+
+        if self.limits.max_handshakes_per_minute == 0 {
+            anyhow::bail!("limits.max_handshakes_per_minute must be greater than 0");
+        }
+
+        if self.limits.max_concurrent_verifications == 0 {
+            anyhow::bail!("limits.max_concurrent_verifications must be greater than 0");
+        }
+
+        tracing_subscriber::EnvFilter::try_new(&self.logging.filter)
+            .context("logging.filter is not a valid filter")?;
+
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            anyhow::bail!("telemetry.sample_ratio must be between 0 and 1");
+        }
+
+        if self.group_digest_key.as_deref() == Some("") {
+            anyhow::bail!("group_digest_key can not be empty");
+        }
+
+        if self.http.admin_token.as_deref() == Some("") {
+            anyhow::bail!("http.admin_token can not be empty");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of settings that differ from `other` but only take effect after a restart
+    pub fn get_restart_required_changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+
+        if self.host != other.host {
+            changes.push("host");
+        }
+
+        if self.port != other.port {
+            changes.push("port");
+        }
+
+        if self.proxy_protocol != other.proxy_protocol {
+            changes.push("proxy_protocol");
+        }
+
+        if self.tls != other.tls {
+            changes.push("tls");
+        }
+
+        if self.bans_file != other.bans_file {
+            changes.push("bans_file");
+        }
+
+        if self.status_broadcast_schedule != other.status_broadcast_schedule {
+            changes.push("status_broadcast_schedule");
+        }
+
+        if self.group_digest_key != other.group_digest_key {
+            changes.push("group_digest_key");
+        }
+
+        if self.steam.name_refresh_schedule != other.steam.name_refresh_schedule {
+            changes.push("steam.name_refresh_schedule");
+        }
+
+        if self.steam.connect_timeout_ms != other.steam.connect_timeout_ms
+            || self.steam.request_timeout_ms != other.steam.request_timeout_ms
+        {
+            changes.push("steam timeouts");
+        }
+
+        if self.steam.circuit_breaker_threshold != other.steam.circuit_breaker_threshold
+            || self.steam.circuit_breaker_cooldown != other.steam.circuit_breaker_cooldown
+        {
+            changes.push("steam circuit breaker");
+        }
+
+        if self.limits.max_concurrent_verifications != other.limits.max_concurrent_verifications {
+            changes.push("limits.max_concurrent_verifications");
+        }
+
+        if self.chat.log != other.chat.log {
+            changes.push("chat.log");
+        }
+
+        if self.http.port != other.http.port {
+            changes.push("http.port");
+        }
+
+        if self.logging.format != other.logging.format {
+            changes.push("logging.format");
+        }
+
+        if self.telemetry != other.telemetry {
+            changes.push("telemetry");
+        }
+
+        changes
+    }
+}
+
+fn apply_env_overrides(
+    value: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), anyhow::Error> {
+    // Tracing errors hold formats that can't be sent across threads, so only their description is kept
+    let registry =
+        trace_config().map_err(|error| anyhow::anyhow!("Could not trace config: {}", error))?;
+
+    for (key, raw_value) in vars {
+        let path = match key.strip_prefix(ENV_PREFIX) {
+            Some(path) => path.to_lowercase(),
+            None => continue,
+        };
+
+        let keys: Vec<&str> = path.split(ENV_SEPARATOR).collect();
+        let (last_key, parent_keys) = keys.split_last().unwrap();
+        let mut table = value.as_table_mut().unwrap();
+
+        for parent_key in parent_keys {
+            table = table
+                .entry(parent_key.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                .as_table_mut()
+                .with_context(|| format!("{} does not refer to a config section", key))?;
+        }
+
+        // String settings can look like numbers or booleans, for example a numeric admin token
+        let parsed_value = match is_string_setting(&registry, &keys) {
+            true => toml::Value::String(raw_value),
+            false => parse_env_value(&raw_value),
+        };
+
+        table.insert(last_key.to_string(), parsed_value);
+    }
+
+    Ok(())
+}
+
+fn trace_config() -> serde_reflection::Result<Registry> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+
+    // Tracing a type only discovers the first variant of the enums it contains
+    tracer.trace_simple_type::<LogFormat>()?;
+    tracer.trace_simple_type::<Config>()?;
+    tracer.registry()
+}
+
+/// Returns whether the setting at the given keys holds a string, unknown settings are left to deserialization
+fn is_string_setting(registry: &Registry, keys: &[&str]) -> bool {
+    let mut format = Format::TypeName("Config".to_string());
+
+    for key in keys {
+        let fields = match &format {
+            Format::TypeName(name) => match registry.get(name) {
+                Some(ContainerFormat::Struct(fields)) => fields,
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        format = match fields.iter().find(|field| field.name == *key) {
+            Some(field) => field.value.clone(),
+            None => return false,
+        };
+    }
+
+    match format {
+        Format::Option(format) => matches!(*format, Format::Str),
+        format => matches!(format, Format::Str),
+    }
+}
+
+/// Parses the value as a toml value so numbers and booleans keep their type, falling back to a string
+fn parse_env_value(raw_value: &str) -> toml::Value {
+    match toml::from_str::<toml::value::Table>(&format!("value = {}", raw_value)) {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => toml::Value::String(raw_value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, vars: &[(&str, &str)]) -> Result<Config, anyhow::Error> {
+        let mut value: toml::Value = toml::from_str(file).unwrap();
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        apply_env_overrides(&mut value, vars)?;
+        Ok(value.try_into()?)
+    }
+
+    #[test]
+    fn overrides_typed_values() {
+        let config = load(
+            "",
+            &[
+                ("JKMP_PORT", "4000"),
+                ("JKMP_PROXY_PROTOCOL", "true"),
+                ("JKMP_CHAT__MAX_MESSAGE_LENGTH", "50"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 4000);
+        assert!(config.proxy_protocol);
+        assert_eq!(config.chat.max_message_length, 50);
+    }
+
+    #[test]
+    fn overrides_nested_values_from_file() {
+        let config = load(
+            "port = 1234\n[chat]\nmax_message_length = 10",
+            &[("JKMP_CHAT__MAX_MESSAGE_LENGTH", "20")],
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 1234);
+        assert_eq!(config.chat.max_message_length, 20);
+    }
+
+    #[test]
+    fn keeps_number_and_bool_like_strings() {
+        let config = load(
+            "",
+            &[
+                ("JKMP_HTTP__ADMIN_TOKEN", "12345"),
+                ("JKMP_GROUP_DIGEST_KEY", "true"),
+                ("JKMP_HOST", "127.0.0.1"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.http.admin_token.as_deref(), Some("12345"));
+        assert_eq!(config.group_digest_key.as_deref(), Some("true"));
+        assert_eq!(config.host, "127.0.0.1");
+    }
+
+    #[test]
+    fn keeps_strings_after_invalid_values() {
+        let mut value = toml::Value::Table(toml::value::Table::new());
+        let vars = [
+            ("JKMP_PORT", "not a port"),
+            ("JKMP_HTTP__ADMIN_TOKEN", "12345"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        apply_env_overrides(&mut value, vars).unwrap();
+
+        assert_eq!(value["http"]["admin_token"].as_str(), Some("12345"));
+    }
+
+    #[test]
+    fn ignores_unprefixed_variables() {
+        let config = load("", &[("PORT", "4000"), ("JKMP", "1")]).unwrap();
+        assert_eq!(config.port, Config::default().port);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(load("", &[("JKMP_PORT", "not a port")]).is_err());
+        assert!(load("", &[("JKMP_PORT", "70000")]).is_err());
+        assert!(load("", &[("JKMP_UNKNOWN", "1")]).is_err());
+    }
+
+    #[test]
+    fn validates_max_message_size() {
+        let mut config = Config::default();
+        config.steam.api_key = Some("key".to_string());
+        assert!(config.validate().is_ok());
+
+        config.protocol.max_message_size = 63;
+        assert!(config.validate().is_err());
+
+        config.protocol.max_message_size = 65536;
+        assert!(config.validate().is_ok());
+
+        config.protocol.max_message_size = 65537;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_overrides_of_non_sections() {
+        assert!(load("", &[("JKMP_PORT__VALUE", "1")]).is_err());
+    }
+}
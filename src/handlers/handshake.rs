@@ -1,127 +1,395 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{convert::TryInto, net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Context;
-use futures::SinkExt;
-use tokio::{
-    net::TcpStream,
-    sync::{mpsc, Mutex},
-};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, Mutex, MutexGuard};
 use tokio_util::codec::Framed;
 
 use crate::{
-    chat::ChatChannel,
     client::{self, Client},
-    codec::MessagesCodec,
+    codec::{self, Format, MessagesCodec},
+    config, limits,
     messages::{
-        HandshakeRequest, HandshakeResponse, Message, OutgoingChatMessage, ServerStatusUpdate,
+        ChatHistory, CompressionAlgorithm, HandshakeError, HandshakeRequest, HandshakeResponse,
+        Message, NoticeCode, NoticeSeverity, OutgoingChatMessage, ProtocolNegotiated,
+        QueuePosition, RetryAfter, ServerNotice, ServerStatusUpdate, SessionToken,
     },
+    metrics,
     state::{MatchmakingOptions, State},
     steam,
+    stream::ClientStream,
+    MessageType,
 };
 
+#[tracing::instrument(name = "handshake", skip_all)]
 pub async fn handle_message(
     message: &HandshakeRequest,
-    tx: mpsc::UnboundedSender<Message>,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    tx: mpsc::UnboundedSender<MessageType>,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
+    connection_span: &tracing::Span,
 ) -> Result<(), anyhow::Error> {
-    if message.version != client::VERSION {
-        send_response(
+    let version = match client::negotiate_version(message.version) {
+        Some(version) => version,
+        None => {
+            // Only send what the oldest supported clients can decode
+            messages
+                .codec_mut()
+                .set_version(client::MIN_SUPPORTED_VERSION);
+            reject(messages, HandshakeError::ClientOutdated).await?;
+            anyhow::bail!("Client version {} is not supported", message.version);
+        }
+    };
+
+    // Allows sending messages of the negotiated version before the handshake response
+    messages.codec_mut().set_version(version);
+
+    if let Err(retry_after) = limits::record_handshake_attempt(source.ip()) {
+        reject_with_retry_after(
             messages,
-            HandshakeResponse {
-                success: false,
-                error_message: Some("Your version is outdated".to_string()),
-            },
+            HandshakeError::RateLimited,
+            Some(retry_after.as_secs() + 1),
         )
         .await?;
-        anyhow::bail!("Client version {} mismatch", message.version);
+        anyhow::bail!("Too many handshake attempts from {}", source.ip());
+    }
+
+    let maintenance_retry_after = {
+        let state = lock_state(state).await;
+        state
+            .is_maintenance()
+            .then(|| state.get_maintenance_retry_after())
+    };
+
+    if let Some(retry_after) = maintenance_retry_after {
+        reject_with_retry_after(messages, HandshakeError::Maintenance, retry_after).await?;
+        anyhow::bail!("Server is in maintenance mode");
     }
 
     match steam::verify_user_auth_ticket(&message.auth_session_ticket).await {
         Ok(ids) => {
-            let user_infos = steam::get_player_summaries(vec![ids.steam_id]).await?;
-            let user_info = user_infos
-                .get(&ids.steam_id)
-                .context("Could not get user info from steam")?;
+            // Check the owner as well so bans can't be evaded through family sharing
+            let is_banned = {
+                let state = lock_state(state).await;
+                let bans = state.get_bans();
+                bans.is_banned(ids.steam_id) || bans.is_banned(ids.owner_steam_id)
+            };
+
+            if is_banned {
+                reject(messages, HandshakeError::Banned).await?;
+                tracing::info!(
+                    event = "auth_failure",
+                    reason = "banned",
+                    "{} failed to auth",
+                    source
+                );
+                anyhow::bail!("{} is banned", ids);
+            }
+
+            let user_info = match steam::cache::get_player_summary(ids.steam_id).await {
+                Ok(user_info) => user_info,
+                Err(error) => {
+                    let handshake_error = match error.is::<steam::SteamUnavailable>() {
+                        true => HandshakeError::SteamUnavailable,
+                        false => HandshakeError::InternalError,
+                    };
+
+                    reject(messages, handshake_error).await?;
+                    return Err(error.context("Could not get user info from steam"));
+                }
+            };
 
             let name = &user_info.name;
 
-            let mut state = state.lock().await;
-            let client = Client::new(tx, ids.steam_id, name.clone(), message.position);
-            tracing::info!("{} connected", client);
+            let client = Client::new(tx, ids.steam_id, name.clone(), message.position, version);
+            connection_span.record("steam_id", ids.steam_id);
+            connection_span.record("player_name", name.as_str());
+            connection_span.record("protocol_version", version);
 
             let matchmaking_options = MatchmakingOptions::new(
                 message.matchmaking_password.clone(),
                 message.level_name.clone(),
             );
+            connection_span.record("group", matchmaking_options.digest().as_str());
 
-            let clients_total = state.get_clients_iter().len();
-            let clients_in_group = state.get_clients_in_group(&matchmaking_options).count();
-
-            let welcome_message = match (clients_total, clients_in_group) {
-                (0, 0) => "Welcome! There are currently no other players online.".into(),
-                (total, 0) => format!(
-                    "Welcome! There are {} other players online, but none of them in your group.",
-                    total
-                ),
-                (total, group) => format!(
-                    "Welcome! There are {} other players online. {} of them are in your group.",
-                    total, group
-                ),
-            };
+            let resume_token = client.resume_token;
 
-            state.add_client(source, client, matchmaking_options);
+            // Only hold the lock while adding the client, sending to a slow client must not block everyone else
+            let (welcome_message, chat_history, status_update) = {
+                let mut state = wait_for_admission(messages, state, ids.steam_id).await?;
+                tracing::info!(event = "connect", "{} connected", client);
 
-            send_response(
-                messages,
-                HandshakeResponse {
-                    success: true,
-                    error_message: None,
-                },
-            )
-            .await?;
+                // Don't count a previous session of the player that is about to be replaced
+                let clients_total = state
+                    .get_clients_iter()
+                    .filter(|(_, other)| other.steam_id != ids.steam_id)
+                    .count();
+                let clients_in_group = state
+                    .get_clients_in_group(&matchmaking_options)
+                    .filter(|other| other.steam_id != ids.steam_id)
+                    .count();
+
+                let welcome_message = match (clients_total, clients_in_group) {
+                    (0, 0) => "Welcome! There are currently no other players online.".into(),
+                    (total, 0) => format!(
+                        "Welcome! There are {} other players online, but none of them in your group.",
+                        total
+                    ),
+                    (total, group) => format!(
+                        "Welcome! There are {} other players online. {} of them are in your group.",
+                        total, group
+                    ),
+                };
+
+                let previous_client =
+                    match state.add_client(source, client, matchmaking_options.clone()) {
+                        Ok(previous_client) => previous_client,
+                        Err(error) => {
+                            drop(state);
+                            reject(messages, HandshakeError::InternalError).await?;
+                            return Err(error.context("Could not add client"));
+                        }
+                    };
+
+                if let Some(previous_client) = previous_client {
+                    tracing::info!(
+                        "{} logged in elsewhere, disconnecting previous session",
+                        previous_client
+                    );
+
+                    // Ignore failed sends, the previous session may have already lost its connection
+                    let _ = previous_client.notify(ServerNotice::new(
+                        NoticeSeverity::Error,
+                        NoticeCode::LoggedInElsewhere,
+                    ));
+                    let _ = previous_client.disconnect();
+                }
+
+                // Messages sent after this point are queued and sent after the chat history
+                let chat_history: Vec<OutgoingChatMessage> = state
+                    .get_chat_history(&matchmaking_options)
+                    .into_iter()
+                    .cloned()
+                    .collect();
 
+                let status_update = ServerStatusUpdate {
+                    total_players: state.get_clients_iter().len() as u32,
+                    group_players: state.get_clients_in_group(&matchmaking_options).count() as u32,
+                };
+
+                (welcome_message, chat_history, status_update)
+            };
+
+            send_response(messages, HandshakeResponse::accepted()).await?;
+            metrics::HANDSHAKES.with_label_values(&["success"]).inc();
+
+            send_protocol_negotiated(messages, version, &message.compression).await?;
             messages
-                .send(Message::OutgoingChatMessage(OutgoingChatMessage {
-                    channel: ChatChannel::Global,
-                    sender_id: None,
-                    sender_name: None,
-                    message: welcome_message,
+                .send(Message::SessionToken(SessionToken {
+                    token: resume_token.encode(),
                 }))
                 .await?;
 
+            // Replay recent chat messages so the player has some context of ongoing conversations
+            for history_message in chat_history {
+                messages
+                    .send(Message::ChatHistory(ChatHistory {
+                        message: history_message,
+                    }))
+                    .await?;
+            }
+
             messages
-                .send(Message::ServerStatusUpdate(ServerStatusUpdate {
-                    total_players: state.get_clients_iter().len() as u32,
-                    group_players: state
-                        .get_clients_in_group(state.get_matchmaking_options(source))
-                        .count() as u32,
-                }))
+                .send(Message::OutgoingChatMessage(OutgoingChatMessage::system(
+                    welcome_message,
+                )))
+                .await?;
+
+            messages
+                .send(Message::ServerStatusUpdate(status_update))
                 .await?;
         }
         Err(error) => {
-            tracing::info!("{} failed to auth: {}", source, error);
-
-            send_response(
-                messages,
-                HandshakeResponse {
-                    success: false,
-                    error_message: Some(
-                        "An unexpected error occured when handling handshake request".to_string(),
-                    ),
-                },
-            )
-            .await?;
+            tracing::info!(
+                event = "auth_failure",
+                reason = %error,
+                "{} failed to auth",
+                source
+            );
+
+            let handshake_error = if error.is::<steam::SteamUnavailable>() {
+                HandshakeError::SteamUnavailable
+            } else if error.is::<steam::TooManyVerifications>() {
+                HandshakeError::RateLimited
+            } else {
+                HandshakeError::AuthFailed
+            };
+
+            reject(messages, handshake_error).await?;
+            return Err(error.context("Failed to verify auth session ticket"));
         }
     }
 
     Ok(())
 }
 
-#[inline]
-async fn send_response(
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+/// Announces the negotiated version and compression, both apply to every frame after this message
+pub async fn send_protocol_negotiated(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    version: u32,
+    accepted_compression: &[CompressionAlgorithm],
+) -> Result<(), anyhow::Error> {
+    // Json frames have no flags to mark compressed payloads
+    let can_compress = version >= codec::FRAME_FLAGS_VERSION
+        && config::get().protocol.compression
+        && messages.codec().format() == Format::Bincode;
+
+    let compression = match can_compress {
+        true => accepted_compression
+            .iter()
+            .find(|algorithm| codec::SUPPORTED_COMPRESSION.contains(algorithm))
+            .copied(),
+        false => None,
+    };
+
+    tracing::debug!("Selected compression {:?}", compression);
+
+    messages
+        .send(Message::ProtocolNegotiated(ProtocolNegotiated {
+            version,
+            compression,
+        }))
+        .await?;
+
+    if version >= codec::FRAME_FLAGS_VERSION {
+        let codec = messages.codec_mut();
+        codec.enable_frame_flags();
+        codec.set_compression(compression);
+    }
+
+    Ok(())
+}
+
+/// Waits until the player can join without exceeding the player limit, informing them about their position in the queue.
+/// Returns the locked state once admitted so the free slot can't be taken before the player is added.
+async fn wait_for_admission<'a>(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    state: &'a Arc<Mutex<State>>,
+    steam_id: u64,
+) -> Result<MutexGuard<'a, State>, anyhow::Error> {
+    let ticket = {
+        let mut state_guard = lock_state(state).await;
+
+        // Players that arrive while others are waiting join the end of the queue even if a slot is free
+        if state_guard.get_admission_queue_len() == 0 && !state_guard.is_full(steam_id) {
+            return Ok(state_guard);
+        }
+
+        if state_guard.get_admission_queue_len() >= config::get().admission.queue_length {
+            drop(state_guard);
+            let retry_after = config::get().admission.retry_after;
+            reject_with_retry_after(messages, HandshakeError::ServerFull, Some(retry_after))
+                .await?;
+            anyhow::bail!("Server is full");
+        }
+
+        state_guard.join_admission_queue()
+    };
+
+    let result = wait_in_admission_queue(messages, state, steam_id, ticket).await;
+
+    if result.is_err() {
+        lock_state(state).await.leave_admission_queue(ticket);
+    }
+
+    result
+}
+
+async fn wait_in_admission_queue<'a>(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    state: &'a Arc<Mutex<State>>,
+    steam_id: u64,
+    ticket: u64,
+) -> Result<MutexGuard<'a, State>, anyhow::Error> {
+    let mut last_position = None;
+
+    loop {
+        let position = {
+            let mut state_guard = lock_state(state).await;
+
+            if state_guard.is_maintenance() {
+                let retry_after = state_guard.get_maintenance_retry_after();
+                drop(state_guard);
+                reject_with_retry_after(messages, HandshakeError::Maintenance, retry_after).await?;
+                anyhow::bail!("Server is in maintenance mode");
+            }
+
+            let position = state_guard
+                .get_admission_queue_position(ticket)
+                .context("Ticket is not in the admission queue")?;
+
+            if position == 0 && !state_guard.is_full(steam_id) {
+                state_guard.leave_admission_queue(ticket);
+                return Ok(state_guard);
+            }
+
+            position
+        };
+
+        if last_position != Some(position) {
+            messages
+                .send(Message::QueuePosition(QueuePosition {
+                    position: position as u32 + 1,
+                }))
+                .await?;
+            last_position = Some(position);
+        }
+
+        // Clients are not expected to send anything while waiting, so any message or disconnect ends the wait
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+            _ = messages.next() => anyhow::bail!("Client left the admission queue"),
+        }
+    }
+}
+
+/// Rejects the handshake, telling the client when to try again if known
+pub async fn reject_with_retry_after(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    error: HandshakeError,
+    retry_after: Option<u64>,
+) -> Result<(), anyhow::Error> {
+    if let Some(retry_after) = retry_after {
+        messages
+            .send(Message::RetryAfter(RetryAfter {
+                seconds: retry_after.try_into().unwrap_or(u32::MAX),
+            }))
+            .await?;
+    }
+
+    reject(messages, error).await
+}
+
+/// Sends a response rejecting the handshake and records the reason
+pub async fn reject(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    error: HandshakeError,
+) -> Result<(), anyhow::Error> {
+    metrics::HANDSHAKES.with_label_values(&[error.name()]).inc();
+    let version = messages.codec().version();
+    let response = HandshakeResponse::rejected(error.for_version(version)).for_version(version);
+    send_response(messages, response).await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn lock_state(state: &Arc<Mutex<State>>) -> MutexGuard<'_, State> {
+    state.lock().await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn send_response(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
     response: HandshakeResponse,
 ) -> Result<(), anyhow::Error> {
     messages.send(Message::HandshakeResponse(response)).await
@@ -1,40 +1,53 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Context;
-use tokio::{net::TcpStream, sync::Mutex};
+use tokio::sync::Mutex;
 use tokio_util::codec::Framed;
 
+use super::RequestFailed;
 use crate::{
     chat::ChatChannel,
     client::Client,
     codec::MessagesCodec,
-    messages::{IncomingChatMessage, Message, OutgoingChatMessage},
+    config,
+    messages::{IncomingChatMessage, Message, OutgoingChatMessage, RequestErrorCode},
     state::State,
+    stream::ClientStream,
     util::string::truncate,
 };
 
 pub async fn handle_message(
     message: &IncomingChatMessage,
-    _messages: &mut Framed<TcpStream, MessagesCodec>,
+    _messages: &mut Framed<ClientStream, MessagesCodec>,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
-    // Trim the incoming message from whitespace and limit it to 100 characters
-    let trimmed_message = truncate(message.message.trim(), 100);
+    // Trim the incoming message from whitespace and limit its length
+    let trimmed_message = truncate(
+        message.message.trim(),
+        config::get().chat.max_message_length,
+    );
 
     // Ignore empty messages
     if trimmed_message.is_empty() {
         return Ok(());
     }
 
-    let state = state.lock().await;
+    let mut state = state.lock().await;
     let client = state.get_client(source).context("Client not found")?;
+    let matchmaking_options = state
+        .get_matchmaking_options(source)
+        .context("Client not found")?
+        .clone();
 
     tracing::info!(
+        event = "chat",
+        channel = ?message.channel,
+        text = trimmed_message,
         "[{:?}] <{}> {}",
         message.channel,
         client.name,
-        message.message
+        trimmed_message
     );
 
     // List of clients to send the message to
@@ -47,11 +60,11 @@ pub async fn handle_message(
             }
         }
         ChatChannel::Group => {
-            for other_client in state.get_clients_in_group(state.get_matchmaking_options(source)) {
+            for other_client in state.get_clients_in_group(&matchmaking_options) {
                 target_clients.push(other_client);
             }
         }
-        _ => anyhow::bail!("Unexpected channel"),
+        _ => return Err(RequestFailed(RequestErrorCode::InvalidChannel).into()),
     }
 
     let outgoing_chat_message = OutgoingChatMessage {
@@ -66,5 +79,7 @@ pub async fn handle_message(
         let _ = other_client.send(Message::OutgoingChatMessage(outgoing_chat_message.clone()));
     }
 
+    state.add_chat_message(&matchmaking_options, outgoing_chat_message);
+
     Ok(())
 }
@@ -1,32 +1,249 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{convert::TryFrom, fmt::Display, net::SocketAddr, sync::Arc};
 
-use tokio::{net::TcpStream, sync::Mutex};
+use futures::SinkExt;
+use tokio::sync::Mutex;
 use tokio_util::codec::Framed;
 
-use crate::{codec::MessagesCodec, messages::Message, state::State};
+use crate::{
+    codec::MessagesCodec,
+    messages::{Ack, Message, Request, RequestError, RequestErrorCode, RequestMessage},
+    state::State,
+    stream::ClientStream,
+};
 
 pub mod handshake;
 pub mod incoming_chat_message;
 pub mod position_update;
+pub mod resume_session;
 pub mod set_matchmaking_password;
 
+/// Error caused by the client sending an invalid message, reported to the client as a `RequestError`
+#[derive(Debug)]
+pub struct RequestFailed(pub RequestErrorCode);
+
+impl Display for RequestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.description())
+    }
+}
+
+impl std::error::Error for RequestFailed {}
+
 pub async fn handle_message(
-    message: &Message,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    message: Message,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
     tracing::trace!("handling message: {:?}", message);
     match message {
-        Message::PositionUpdate(val) => {
+        Message::Request(request) => handle_request(&request, messages, source, state).await,
+        message => match RequestMessage::try_from(message) {
+            Ok(message) => dispatch_message(&message, messages, source, state).await,
+            Err(_) => Err(RequestFailed(RequestErrorCode::UnexpectedMessage).into()),
+        },
+    }
+}
+
+/// Handles the message in the request and replies with an `Ack` or `RequestError`.
+/// Errors caused by the client are only reported to the client, other errors still close the connection.
+async fn handle_request(
+    request: &Request,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    source: &SocketAddr,
+    state: &Arc<Mutex<State>>,
+) -> Result<(), anyhow::Error> {
+    let error = match dispatch_message(&request.message, messages, source, state).await {
+        Ok(()) => return messages.send(Message::Ack(Ack { id: request.id })).await,
+        Err(error) => error,
+    };
+
+    let code = error
+        .downcast_ref::<RequestFailed>()
+        .map(|request_failed| request_failed.0);
+
+    messages
+        .send(Message::RequestError(RequestError {
+            id: request.id,
+            code: code.unwrap_or(RequestErrorCode::InternalError),
+        }))
+        .await?;
+
+    match code {
+        Some(_) => {
+            tracing::debug!("Request {} failed: {}", request.id, error);
+            Ok(())
+        }
+        None => Err(error),
+    }
+}
+
+async fn dispatch_message(
+    message: &RequestMessage,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    source: &SocketAddr,
+    state: &Arc<Mutex<State>>,
+) -> Result<(), anyhow::Error> {
+    match message {
+        RequestMessage::PositionUpdate(val) => {
             position_update::handle_message(val, messages, source, state).await
         }
-        Message::SetMatchmakingPassword(val) => {
+        RequestMessage::SetMatchmakingPassword(val) => {
             set_matchmaking_password::handle_message(val, messages, source, state).await
         }
-        Message::IncomingChatMessage(val) => {
+        RequestMessage::IncomingChatMessage(val) => {
             incoming_chat_message::handle_message(val, messages, source, state).await
         }
-        _ => anyhow::bail!("Unexpected message"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        chat::ChatChannel,
+        client::Client,
+        math::Vector2,
+        messages::{IncomingChatMessage, PositionUpdate},
+        state::MatchmakingOptions,
+    };
+
+    /// Server side of a connection and the client side to read the replies from
+    struct Connection {
+        messages: Framed<ClientStream, MessagesCodec>,
+        client: Framed<TcpStream, jkmp_client::codec::MessagesCodec>,
+        source: SocketAddr,
+        state: Arc<Mutex<State>>,
+    }
+
+    impl Connection {
+        /// Connects a client that has completed its handshake
+        async fn open() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (socket, source) = listener.accept().await.unwrap();
+
+            let mut state = State::new();
+            let (tx, _rx) = mpsc::unbounded_channel();
+            let position = Vector2 { x: 0.0, y: 0.0 };
+            state
+                .add_client(
+                    &source,
+                    Client::new(
+                        tx,
+                        1,
+                        "Player".to_string(),
+                        position,
+                        crate::client::VERSION,
+                    ),
+                    MatchmakingOptions::new(None, "Level".to_string()),
+                )
+                .unwrap();
+
+            Self {
+                messages: Framed::new(ClientStream::Plain(socket), MessagesCodec::new()),
+                client: Framed::new(stream, Default::default()),
+                source,
+                state: Arc::new(Mutex::new(state)),
+            }
+        }
+
+        async fn handle(&mut self, message: Message) -> Result<(), anyhow::Error> {
+            handle_message(message, &mut self.messages, &self.source, &self.state).await
+        }
+
+        async fn receive(&mut self) -> Message {
+            self.client.next().await.unwrap().unwrap()
+        }
+    }
+
+    fn request(id: u32, message: RequestMessage) -> Message {
+        Message::Request(Request { id, message })
+    }
+
+    fn position_update() -> RequestMessage {
+        RequestMessage::PositionUpdate(PositionUpdate {
+            position: Vector2 { x: 1.0, y: 1.0 },
+        })
+    }
+
+    #[tokio::test]
+    async fn acknowledges_handled_requests() {
+        let mut connection = Connection::open().await;
+
+        connection
+            .handle(request(7, position_update()))
+            .await
+            .unwrap();
+
+        match connection.receive().await {
+            Message::Ack(ack) => assert_eq!(ack.id, 7),
+            message => panic!("Expected an ack, received {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_client_errors_without_failing() {
+        let mut connection = Connection::open().await;
+        let message = RequestMessage::IncomingChatMessage(IncomingChatMessage {
+            channel: ChatChannel::Local,
+            message: "Hello".to_string(),
+        });
+
+        connection.handle(request(8, message)).await.unwrap();
+
+        match connection.receive().await {
+            Message::RequestError(error) => {
+                assert_eq!(error.id, 8);
+                assert_eq!(error.code, RequestErrorCode::InvalidChannel);
+            }
+            message => panic!("Expected a request error, received {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_internal_errors_and_fails() {
+        let mut connection = Connection::open().await;
+        connection
+            .state
+            .lock()
+            .await
+            .remove_client(&connection.source);
+
+        assert!(connection
+            .handle(request(9, position_update()))
+            .await
+            .is_err());
+
+        match connection.receive().await {
+            Message::RequestError(error) => {
+                assert_eq!(error.id, 9);
+                assert_eq!(error.code, RequestErrorCode::InternalError);
+            }
+            message => panic!("Expected a request error, received {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_messages() {
+        let mut connection = Connection::open().await;
+
+        let error = connection
+            .handle(Message::Ack(Ack { id: 1 }))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<RequestFailed>(),
+            Some(RequestFailed(RequestErrorCode::UnexpectedMessage))
+        ));
     }
 }
@@ -0,0 +1,127 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use futures::SinkExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::codec::Framed;
+
+use crate::{
+    client,
+    codec::MessagesCodec,
+    limits,
+    messages::{
+        HandshakeError, HandshakeResponse, Message, ResumeSession, ServerStatusUpdate, SessionToken,
+    },
+    metrics,
+    session::{self, ResumeToken},
+    state::State,
+    stream::ClientStream,
+    MessageType,
+};
+
+use super::handshake::{
+    lock_state, reject, reject_with_retry_after, send_protocol_negotiated, send_response,
+};
+
+#[tracing::instrument(name = "resume_session", skip_all)]
+pub async fn handle_message(
+    message: &ResumeSession,
+    tx: mpsc::UnboundedSender<MessageType>,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    source: &SocketAddr,
+    state: &Arc<Mutex<State>>,
+    connection_span: &tracing::Span,
+) -> Result<(), anyhow::Error> {
+    let version = match client::negotiate_version(message.version) {
+        Some(version) if version >= session::PROTOCOL_VERSION => version,
+        version => {
+            // Only send what the client can decode
+            messages
+                .codec_mut()
+                .set_version(version.unwrap_or(client::MIN_SUPPORTED_VERSION));
+            reject(messages, HandshakeError::ClientOutdated).await?;
+            anyhow::bail!("Client version {} can not resume sessions", message.version);
+        }
+    };
+
+    messages.codec_mut().set_version(version);
+
+    if let Err(retry_after) = limits::record_handshake_attempt(source.ip()) {
+        reject_with_retry_after(
+            messages,
+            HandshakeError::RateLimited,
+            Some(retry_after.as_secs() + 1),
+        )
+        .await?;
+        anyhow::bail!("Too many handshake attempts from {}", source.ip());
+    }
+
+    let token = match ResumeToken::decode(&message.token) {
+        Some(token) => token,
+        None => {
+            reject(messages, HandshakeError::InvalidSession).await?;
+            anyhow::bail!("Invalid resume token");
+        }
+    };
+
+    // Only hold the lock while resuming the client, sending to a slow client must not block everyone else
+    let (resume_token, status_update) = {
+        let mut state = lock_state(state).await;
+
+        if state.get_bans().is_banned(token.steam_id) {
+            drop(state);
+            reject(messages, HandshakeError::Banned).await?;
+            anyhow::bail!("{} is banned", token.steam_id);
+        }
+
+        let client = match state.resume_client(&token, source) {
+            Ok(Some(client)) => client,
+            Err(error) => {
+                drop(state);
+                reject(messages, HandshakeError::InternalError).await?;
+                return Err(error.context("Could not resume session"));
+            }
+            Ok(None) => {
+                drop(state);
+                reject(messages, HandshakeError::InvalidSession).await?;
+                anyhow::bail!("No session to resume for {}", token.steam_id);
+            }
+        };
+
+        client.reconnect(tx, version);
+        let resume_token = client.resume_token;
+
+        connection_span.record("steam_id", client.steam_id);
+        connection_span.record("player_name", client.name.as_str());
+        connection_span.record("protocol_version", version);
+        tracing::info!(event = "resume", "{} resumed session", client);
+
+        let matchmaking_options = state
+            .get_matchmaking_options(source)
+            .context("Client not found")?;
+        connection_span.record("group", matchmaking_options.digest().as_str());
+
+        let status_update = ServerStatusUpdate {
+            total_players: state.get_clients_iter().len() as u32,
+            group_players: state.get_clients_in_group(matchmaking_options).count() as u32,
+        };
+
+        (resume_token, status_update)
+    };
+
+    send_response(messages, HandshakeResponse::accepted()).await?;
+    metrics::HANDSHAKES.with_label_values(&["resumed"]).inc();
+
+    send_protocol_negotiated(messages, version, &message.compression).await?;
+    messages
+        .send(Message::SessionToken(SessionToken {
+            token: resume_token.encode(),
+        }))
+        .await?;
+
+    messages
+        .send(Message::ServerStatusUpdate(status_update))
+        .await?;
+
+    Ok(())
+}
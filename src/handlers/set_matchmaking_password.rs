@@ -1,26 +1,34 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::{net::TcpStream, sync::Mutex};
+use anyhow::Context;
+use tokio::sync::Mutex;
 use tokio_util::codec::Framed;
 
 use crate::{
     codec::MessagesCodec,
     messages::SetMatchmakingPassword,
     state::{MatchmakingOptions, State},
+    stream::ClientStream,
 };
 
 pub async fn handle_message(
     message: &SetMatchmakingPassword,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
     source: &SocketAddr,
     state: &Arc<Mutex<State>>,
 ) -> Result<(), anyhow::Error> {
     let mut state = state.lock().await;
-    let level_name = state.get_matchmaking_options(source).level_name.clone();
+    // The client is removed from the state while its connection is still open when the session is replaced
+    let level_name = state
+        .get_matchmaking_options(source)
+        .context("Client not found")?
+        .level_name
+        .clone();
     let matchmaking_options = MatchmakingOptions::new(message.password.clone(), level_name);
     state.set_matchmaking_options(source, Some(matchmaking_options.clone()));
+    tracing::Span::current().record("group", matchmaking_options.digest().as_str());
 
-    let client = state.get_client(source).unwrap();
+    let client = state.get_client(source).context("Client not found")?;
     let nearby_clients = state.get_nearby_clients(&client.position, &matchmaking_options);
 
     if nearby_clients.len() > 1 {
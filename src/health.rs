@@ -0,0 +1,33 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::steam;
+
+/// Tracks whether the server is able to accept new players
+#[derive(Default)]
+pub struct Health {
+    listening: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    /// Marks the server as shutting down, so no new players should be routed to it
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_listening() && !self.is_draining() && steam::is_available()
+    }
+}
@@ -0,0 +1,298 @@
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    bans::{Ban, BanList},
+    math::Vector2,
+    messages::{Message, NoticeCode, NoticeSeverity, OutgoingChatMessage, ServerNotice},
+    state::State,
+};
+
+use super::{error_response, json_response, read_json, HttpContext};
+
+#[derive(Serialize)]
+struct ClientInfo {
+    address: String,
+    steam_id: u64,
+    name: String,
+    position: Vector2,
+    protocol_version: u32,
+    level_name: String,
+    group: String,
+}
+
+#[derive(Serialize)]
+struct GroupInfo {
+    group: String,
+    level_name: String,
+    has_password: bool,
+    members: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct BanInfo {
+    steam_id: u64,
+    #[serde(flatten)]
+    ban: Ban,
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastRequest {
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MaintenanceStatus {
+    enabled: bool,
+    /// Seconds after which players are told to try again
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    reason: Option<String>,
+}
+
+pub async fn handle_request(
+    request: Request<Body>,
+    route: &[&str],
+    context: &HttpContext,
+) -> Result<Response<Body>, anyhow::Error> {
+    match (request.method(), route) {
+        (&Method::GET, ["clients"]) => list_clients(context).await,
+        (&Method::POST, ["clients", steam_id, "kick"]) => {
+            let steam_id = steam_id.parse()?;
+            let request = read_json_or_default(request, KickRequest { reason: None }).await?;
+            kick_client(context, steam_id, request.reason).await
+        }
+        (&Method::GET, ["groups"]) => list_groups(context).await,
+        (&Method::POST, ["broadcast"]) => broadcast(context, read_json(request).await?).await,
+        (&Method::GET, ["maintenance"]) => get_maintenance(context).await,
+        (&Method::PUT, ["maintenance"]) => {
+            set_maintenance(context, read_json(request).await?).await
+        }
+        (&Method::GET, ["bans"]) => list_bans(context).await,
+        (&Method::PUT, ["bans", steam_id]) => {
+            let steam_id = steam_id.parse()?;
+            let request = read_json_or_default(request, BanRequest { reason: None }).await?;
+            add_ban(context, steam_id, request.reason).await
+        }
+        (&Method::DELETE, ["bans", steam_id]) => remove_ban(context, steam_id.parse()?).await,
+        _ => Ok(error_response(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+/// Reads the json body of the request, or returns the default value if the body is empty
+async fn read_json_or_default<T: DeserializeOwned>(
+    request: Request<Body>,
+    default: T,
+) -> Result<T, anyhow::Error> {
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+
+    if body.is_empty() {
+        return Ok(default);
+    }
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn list_clients(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    let state = context.state.lock().await;
+    let clients: Vec<ClientInfo> = state
+        .get_clients_iter()
+        .filter_map(|(address, client)| {
+            let matchmaking_options = state.get_matchmaking_options(address)?;
+
+            Some(ClientInfo {
+                address: address.to_string(),
+                steam_id: client.steam_id,
+                name: client.name.clone(),
+                position: client.position,
+                protocol_version: client.version,
+                level_name: matchmaking_options.level_name.clone(),
+                group: matchmaking_options.digest(),
+            })
+        })
+        .collect();
+
+    Ok(json_response(StatusCode::OK, &clients))
+}
+
+async fn kick_client(
+    context: &HttpContext,
+    steam_id: u64,
+    reason: Option<String>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let mut state = context.state.lock().await;
+    if !kick_steam_id(&mut state, steam_id, NoticeCode::Kicked, reason) {
+        return Ok(error_response(StatusCode::NOT_FOUND, "Client not found"));
+    }
+
+    Ok(Response::new(Body::empty()))
+}
+
+/// Disconnects the client with the given steam id, returns false if it isn't connected
+fn kick_steam_id(
+    state: &mut State,
+    steam_id: u64,
+    code: NoticeCode,
+    reason: Option<String>,
+) -> bool {
+    let address = match state.get_client_address(steam_id) {
+        Some(address) => *address,
+        None => return false,
+    };
+
+    // Clients waiting to resume their session have no connection to close
+    if let Some(client) = state.remove_reserved_client(&address) {
+        tracing::info!("Kicking {}", client);
+        return true;
+    }
+
+    let client = state.get_client(&address).unwrap();
+    tracing::info!("Kicking {}", client);
+
+    let mut notice = ServerNotice::new(NoticeSeverity::Error, code);
+
+    if let Some(reason) = &reason {
+        notice.message = format!("{}: {}", notice.message, reason);
+    }
+
+    // Ignore failed sends, the client is already disconnecting in that case
+    let _ = client.notify(notice);
+    let _ = client.disconnect();
+
+    true
+}
+
+async fn list_groups(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    let state = context.state.lock().await;
+    let groups: Vec<GroupInfo> = state
+        .get_groups_iter()
+        .map(|(matchmaking_options, addresses)| GroupInfo {
+            group: matchmaking_options.digest(),
+            level_name: matchmaking_options.level_name.clone(),
+            has_password: matchmaking_options.password.is_some(),
+            members: addresses
+                .iter()
+                .filter_map(|address| state.get_client(address))
+                .map(|client| client.steam_id)
+                .collect(),
+        })
+        .collect();
+
+    Ok(json_response(StatusCode::OK, &groups))
+}
+
+async fn broadcast(
+    context: &HttpContext,
+    request: BroadcastRequest,
+) -> Result<Response<Body>, anyhow::Error> {
+    let state = context.state.lock().await;
+    tracing::info!("Broadcasting system message: {}", request.message);
+
+    for (_, client) in state.get_clients_iter() {
+        // Ignore failed sends
+        let _ = client.send(system_message(request.message.clone()));
+    }
+
+    Ok(Response::new(Body::empty()))
+}
+
+async fn get_maintenance(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    let state = context.state.lock().await;
+    Ok(json_response(
+        StatusCode::OK,
+        &MaintenanceStatus {
+            enabled: state.is_maintenance(),
+            retry_after: state.get_maintenance_retry_after(),
+        },
+    ))
+}
+
+async fn set_maintenance(
+    context: &HttpContext,
+    request: MaintenanceStatus,
+) -> Result<Response<Body>, anyhow::Error> {
+    context
+        .state
+        .lock()
+        .await
+        .set_maintenance(request.enabled, request.retry_after);
+    tracing::info!("Maintenance mode set to {}", request.enabled);
+    Ok(json_response(StatusCode::OK, &request))
+}
+
+async fn list_bans(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    let state = context.state.lock().await;
+    let bans: Vec<BanInfo> = state
+        .get_bans()
+        .iter()
+        .map(|(steam_id, ban)| BanInfo {
+            steam_id: *steam_id,
+            ban: ban.clone(),
+        })
+        .collect();
+
+    Ok(json_response(StatusCode::OK, &bans))
+}
+
+async fn add_ban(
+    context: &HttpContext,
+    steam_id: u64,
+    reason: Option<String>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let bans = {
+        let mut state = context.state.lock().await;
+        state.get_bans_mut().add(
+            steam_id,
+            Ban {
+                reason: reason.clone(),
+                created_at: chrono::Utc::now(),
+            },
+        );
+
+        kick_steam_id(&mut state, steam_id, NoticeCode::Banned, reason);
+        state.get_bans().clone()
+    };
+
+    tracing::info!("Banned {}", steam_id);
+    save_bans(context, &bans).await?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+async fn remove_ban(context: &HttpContext, steam_id: u64) -> Result<Response<Body>, anyhow::Error> {
+    let bans = {
+        let mut state = context.state.lock().await;
+
+        if state.get_bans_mut().remove(steam_id).is_none() {
+            return Ok(error_response(StatusCode::NOT_FOUND, "Ban not found"));
+        }
+
+        state.get_bans().clone()
+    };
+
+    tracing::info!("Unbanned {}", steam_id);
+    save_bans(context, &bans).await?;
+
+    Ok(Response::new(Body::empty()))
+}
+
+async fn save_bans(context: &HttpContext, bans: &BanList) -> Result<(), anyhow::Error> {
+    if let Some(bans_file) = &context.bans_file {
+        bans.save(bans_file).await?;
+    }
+
+    Ok(())
+}
+
+fn system_message(message: String) -> Message {
+    Message::OutgoingChatMessage(OutgoingChatMessage::system(message))
+}
@@ -0,0 +1,38 @@
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::steam;
+
+use super::{json_response, HttpContext};
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    listening: bool,
+    steam_available: bool,
+    draining: bool,
+}
+
+pub async fn healthz() -> Result<Response<Body>, anyhow::Error> {
+    Ok(json_response(
+        StatusCode::OK,
+        &serde_json::json!({ "status": "ok" }),
+    ))
+}
+
+pub async fn readyz(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    let health = &context.health;
+    let readiness = Readiness {
+        ready: health.is_ready(),
+        listening: health.is_listening(),
+        steam_available: steam::is_available(),
+        draining: health.is_draining(),
+    };
+
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    Ok(json_response(status, &readiness))
+}
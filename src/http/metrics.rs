@@ -0,0 +1,23 @@
+use hyper::{header, Body, Response};
+use prometheus::{Encoder, TextEncoder};
+
+use crate::metrics;
+
+use super::HttpContext;
+
+pub async fn handle_request(context: &HttpContext) -> Result<Response<Body>, anyhow::Error> {
+    {
+        let state = context.state.lock().await;
+        metrics::CONNECTED_CLIENTS.set(state.get_clients_iter().len() as i64);
+        metrics::ADMISSION_QUEUE_LENGTH.set(state.get_admission_queue_len() as i64);
+        metrics::set_group_sizes(state.get_groups_iter().map(|(_, group)| group.len()));
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))?)
+}
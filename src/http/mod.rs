@@ -0,0 +1,124 @@
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{config, health::Health, state::State};
+
+pub mod admin;
+pub mod health;
+pub mod metrics;
+
+pub struct HttpContext {
+    pub state: Arc<Mutex<State>>,
+    pub health: Arc<Health>,
+    /// File that the ban list is saved to when changed through the admin api
+    pub bans_file: Option<PathBuf>,
+}
+
+/// Serves the http endpoints until the process exits
+pub async fn run(address: SocketAddr, context: HttpContext) -> Result<(), anyhow::Error> {
+    let context = Arc::new(context);
+    let make_service = make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(handle_request(request, context).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&address)?.serve(make_service);
+    tracing::info!("Http server listening on {}", address);
+    server.await?;
+
+    Ok(())
+}
+
+async fn handle_request(request: Request<Body>, context: Arc<HttpContext>) -> Response<Body> {
+    let path = request.uri().path().to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let result = match segments.as_slice() {
+        ["admin", route @ ..] => {
+            if !is_authorized(&request) {
+                return error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+            }
+
+            admin::handle_request(request, route, &context).await
+        }
+        ["metrics"] => metrics::handle_request(&context).await,
+        ["healthz"] => health::healthz().await,
+        ["readyz"] => health::readyz(&context).await,
+        _ => Ok(error_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::warn!("An error occurred when handling http request: {:?}", error);
+            error_response(StatusCode::BAD_REQUEST, &error.to_string())
+        }
+    }
+}
+
+/// Checks the bearer token of the request, the admin endpoints are disabled if no admin token is configured
+fn is_authorized(request: &Request<Body>) -> bool {
+    let config = config::get();
+    let admin_token = match &config.http.admin_token {
+        Some(admin_token) => admin_token,
+        None => return false,
+    };
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) => constant_time_eq(token.as_bytes(), admin_token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares two byte slices without returning early, so the token can't be guessed from response timings
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn read_json<T: DeserializeOwned>(request: Request<Body>) -> Result<T, anyhow::Error> {
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(error) => {
+            tracing::error!("Failed to serialize http response: {:?}", error);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::config;
+
+/// Length of the window in which handshake attempts are counted
+const HANDSHAKE_ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+    static ref HANDSHAKE_ATTEMPTS: Mutex<HandshakeAttempts> =
+        Mutex::new(HandshakeAttempts::default());
+}
+
+struct AttemptWindow {
+    started_at: Instant,
+    attempts: u32,
+}
+
+/// Handshake attempts of every ip address within their current window
+#[derive(Default)]
+struct HandshakeAttempts {
+    windows: HashMap<IpAddr, AttemptWindow>,
+}
+
+impl HandshakeAttempts {
+    fn record(&mut self, ip: IpAddr, max_attempts: u32, now: Instant) -> Result<(), Duration> {
+        self.windows
+            .retain(|_, window| now.duration_since(window.started_at) < HANDSHAKE_ATTEMPT_WINDOW);
+
+        let window = self.windows.entry(ip).or_insert(AttemptWindow {
+            started_at: now,
+            attempts: 0,
+        });
+
+        if window.attempts >= max_attempts {
+            return Err(HANDSHAKE_ATTEMPT_WINDOW - now.duration_since(window.started_at));
+        }
+
+        window.attempts += 1;
+        Ok(())
+    }
+}
+
+/// Counts towards the open connections of an ip address until dropped
+pub struct ConnectionGuard {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().unwrap();
+
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Registers a new connection from the ip address, returns None if the ip address has too many open connections
+pub fn acquire_connection(ip: IpAddr) -> Option<ConnectionGuard> {
+    acquire_connection_with_limit(ip, config::get().limits.max_connections_per_ip)
+}
+
+fn acquire_connection_with_limit(ip: IpAddr, max_connections: usize) -> Option<ConnectionGuard> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let count = connections.entry(ip).or_insert(0);
+
+    if *count >= max_connections {
+        return None;
+    }
+
+    *count += 1;
+    Some(ConnectionGuard { ip })
+}
+
+/// Records a handshake attempt from the ip address.
+/// Returns the time until the next attempt is allowed if the ip address made too many attempts recently.
+pub fn record_handshake_attempt(ip: IpAddr) -> Result<(), Duration> {
+    let max_attempts = config::get().limits.max_handshakes_per_minute;
+    HANDSHAKE_ATTEMPTS
+        .lock()
+        .unwrap()
+        .record(ip, max_attempts, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    // Every test uses its own address since the connection counters are shared by all tests
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let first = acquire_connection_with_limit(ip, 2).unwrap();
+        let _second = acquire_connection_with_limit(ip, 2).unwrap();
+
+        assert!(acquire_connection_with_limit(ip, 2).is_none());
+
+        // Other addresses have their own limit
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(acquire_connection_with_limit(other_ip, 2).is_some());
+
+        drop(first);
+        assert!(acquire_connection_with_limit(ip, 2).is_some());
+    }
+
+    #[test]
+    fn forgets_ip_without_connections() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3));
+        drop(acquire_connection_with_limit(ip, 1).unwrap());
+
+        assert!(!CONNECTIONS.lock().unwrap().contains_key(&ip));
+    }
+
+    #[test]
+    fn limits_handshake_attempts_per_window() {
+        let mut attempts = HandshakeAttempts::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 4));
+        let start = Instant::now();
+
+        assert!(attempts.record(ip, 2, start).is_ok());
+        assert!(attempts.record(ip, 2, start).is_ok());
+
+        let later = start + Duration::from_secs(20);
+        assert_eq!(
+            attempts.record(ip, 2, later),
+            Err(HANDSHAKE_ATTEMPT_WINDOW - Duration::from_secs(20))
+        );
+
+        // Other addresses have their own window
+        let other_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 5));
+        assert!(attempts.record(other_ip, 2, later).is_ok());
+    }
+
+    #[test]
+    fn allows_handshake_attempts_after_window() {
+        let mut attempts = HandshakeAttempts::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 4));
+        let start = Instant::now();
+
+        assert!(attempts.record(ip, 1, start).is_ok());
+        assert!(attempts.record(ip, 1, start).is_err());
+        assert!(attempts
+            .record(ip, 1, start + HANDSHAKE_ATTEMPT_WINDOW)
+            .is_ok());
+    }
+}
@@ -0,0 +1,49 @@
+use serde::Deserialize;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+use crate::{
+    config::{Config, LoggingConfig},
+    telemetry,
+};
+
+pub type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Installs the global subscriber and returns a handle that can be used to change the filter at runtime
+pub fn init(config: &Config) -> Result<FilterHandle, anyhow::Error> {
+    let (filter, handle) = reload::Layer::new(create_filter(&config.logging)?);
+    let json = config.logging.format == LogFormat::Json;
+    let tracer = telemetry::init_tracer(&config.telemetry)?;
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .with(json.then(|| fmt::layer().json().flatten_event(true)))
+        .with((!json).then(fmt::layer))
+        .try_init()?;
+
+    Ok(handle)
+}
+
+pub fn reload_filter(handle: &FilterHandle, config: &LoggingConfig) -> Result<(), anyhow::Error> {
+    handle.reload(create_filter(config)?)?;
+    Ok(())
+}
+
+/// Creates the filter from the config, the RUST_LOG environment variable takes precedence if set
+fn create_filter(config: &LoggingConfig) -> Result<EnvFilter, anyhow::Error> {
+    let directives = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => directives,
+        Err(_) => config.filter.clone(),
+    };
+
+    Ok(EnvFilter::try_new(directives)?)
+}
@@ -1,37 +1,66 @@
+use bytes::{BufMut, BytesMut};
 use futures::{SinkExt, StreamExt};
-use handlers::handshake;
-use std::{net::SocketAddr, sync::Arc};
+use handlers::{handshake, resume_session};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use structopt::StructOpt;
 use tokio::{
+    io::AsyncReadExt,
     net::{TcpListener, TcpStream},
     signal,
     sync::{mpsc, Mutex},
 };
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tokio_util::codec::Decoder;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{Framed, FramedParts};
+use tracing::Instrument;
 
 mod codec;
-use codec::MessagesCodec;
+use codec::{Format, MessagesCodec};
 
-mod messages;
+use jkmp_client::{math, messages};
 use messages::Message;
 
 mod state;
 use state::State;
 
-use crate::messages::ServerStatusUpdate;
+use crate::{
+    bans::BanList,
+    chat::log::{ChatLog, ChatLogOptions},
+    client::Outbound,
+    config::Config,
+    handlers::RequestFailed,
+    health::Health,
+    http::HttpContext,
+    messages::{NoticeCode, NoticeSeverity, PlayerRenamed, ServerNotice, ServerStatusUpdate},
+    stream::ClientStream,
+    tls::Tls,
+};
 
 mod client;
 
 mod handlers;
+mod health;
+mod http;
+mod limits;
+mod logging;
 
+mod bans;
 mod chat;
-mod encoding;
-mod math;
+mod config;
+mod metrics;
+mod proxy_protocol;
+mod schema;
+mod session;
 mod steam;
+mod stream;
+mod telemetry;
+mod tls;
 mod util;
 
-type MessageType = Message;
+#[cfg(test)]
+mod tests;
+
+type MessageType = client::Outbound;
 
 #[derive(StructOpt)]
 #[structopt(
@@ -39,76 +68,228 @@ type MessageType = Message;
     about = "Handles matchmaking between players"
 )]
 struct LaunchOptions {
-    #[structopt(short, long, default_value = "0.0.0.0")]
-    host: String,
+    /// Path to the toml config file
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
 
+    /// Overrides the host from the config file
     #[structopt(short, long)]
-    port: u16,
+    host: Option<String>,
+
+    /// Overrides the port from the config file
+    #[structopt(short, long)]
+    port: Option<u16>,
+
+    /// Prints a json description of the protocol messages and exits
+    #[structopt(long)]
+    dump_schema: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    tracing_subscriber::fmt::init();
+    let options = Arc::new(LaunchOptions::from_args());
 
-    let options = LaunchOptions::from_args();
-
-    if std::env::var("STEAM_API_KEY").is_err() {
-        anyhow::bail!("Environment variable STEAM_API_KEY is missing");
+    if options.dump_schema {
+        println!("{}", schema::dump()?);
+        return Ok(());
     }
 
-    let listener = TcpListener::bind(format!("{}:{}", options.host, options.port)).await?;
+    config::set(load_config(&options)?);
+    let config = config::get();
+    let log_filter_handle = logging::init(&config)?;
+
+    let tls = match (&config.tls.cert_path, &config.tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls = Arc::new(Tls::load(cert_path.clone(), key_path.clone())?);
+            tokio::spawn(tls.clone().watch());
+            Some(tls)
+        }
+        _ => None,
+    };
+
+    let listener = TcpListener::bind(format!("{}:{}", config.host, config.port)).await?;
     let state = Arc::new(Mutex::new(State::new()));
+    let health = Arc::new(Health::default());
+    health.set_listening(true);
+
+    if let Some(directory) = &config.chat.log.directory {
+        let chat_log = ChatLog::open(ChatLogOptions {
+            directory: directory.clone(),
+            max_file_size: config.chat.log.max_file_size,
+            retention: Duration::from_secs(config.chat.log.retention_days * 24 * 60 * 60),
+        })
+        .await?;
+
+        state.lock().await.set_chat_log(Some(chat_log));
+    }
+
+    if let Some(bans_file) = &config.bans_file {
+        *state.lock().await.get_bans_mut() = BanList::load(bans_file).await?;
+    }
+
+    if let Some(http_port) = config.http.port {
+        let address = format!("{}:{}", config.host, http_port).parse()?;
+        let context = HttpContext {
+            state: state.clone(),
+            health: health.clone(),
+            bans_file: config.bans_file.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(error) = http::run(address, context).await {
+                tracing::error!("Http server stopped: {:?}", error);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_config_on_hangup(options.clone(), log_filter_handle));
 
     tracing::info!(
         "Server started, listening for clients on {}:{}",
-        options.host,
-        options.port
+        config.host,
+        config.port
     );
 
     let mut scheduler = JobScheduler::new();
     let scheduler_state = state.clone();
 
-    // Broadcast server status once every minute
-    let broadcast_server_status_job = Job::new_async("1/60 * * * * *", move |_uuid, _l| {
-        let state = scheduler_state.clone();
-        Box::pin(async move {
-            if let Err(error) = broadcast_server_update(state).await {
-                tracing::error!(
-                    "An error occured while broadcasting server status: {}",
-                    error
-                );
-            }
+    // Broadcast server status, once every minute by default
+    let broadcast_server_status_job =
+        Job::new_async(&config.status_broadcast_schedule, move |_uuid, _l| {
+            let state = scheduler_state.clone();
+            Box::pin(async move {
+                if let Err(error) = broadcast_server_update(state).await {
+                    tracing::error!(
+                        "An error occured while broadcasting server status: {}",
+                        error
+                    );
+                }
+            })
         })
-    })
-    .unwrap();
+        .unwrap();
     scheduler.add(broadcast_server_status_job).unwrap();
 
+    let scheduler_state = state.clone();
+    let refresh_player_names_job =
+        Job::new_async(&config.steam.name_refresh_schedule, move |_uuid, _l| {
+            let state = scheduler_state.clone();
+            Box::pin(async move {
+                if let Err(error) = refresh_player_names(state).await {
+                    tracing::error!("An error occured while refreshing player names: {}", error);
+                }
+            })
+        })
+        .unwrap();
+    scheduler.add(refresh_player_names_job).unwrap();
+
     scheduler.start();
 
-    loop {
-        tokio::select! {
-            result = listener.accept() => match result {
-                Err(error) => tracing::info!("An error occurred when accepting socket: {}", error),
-                Ok((socket, address)) => {
-                    let state = state.clone();
-                    tokio::spawn(async move {
-                        process_client(socket, address, state).await;
-                    });
-                }
-            },
-            _ = signal::ctrl_c() => {
-                break;
-            }
-        }
+    tokio::select! {
+        _ = accept_clients(&listener, config.proxy_protocol, tls, state.clone()) => {},
+        _ = signal::ctrl_c() => {},
     }
 
+    drop(listener);
+    health.set_listening(false);
+    health.set_draining(true);
+
     tracing::info!("Server shutting down...");
 
-    // todo: send message about shutdown to clients?
+    for (_, client) in state.lock().await.get_clients_iter() {
+        // Ignore failed sends
+        let _ = client.notify(ServerNotice::new(
+            NoticeSeverity::Warning,
+            NoticeCode::ShuttingDown,
+        ));
+    }
+
+    // Read the config again since the drain timeout may have been changed at runtime
+    let drain_timeout = config::get().drain_timeout;
+
+    if drain_timeout > 0 {
+        tracing::info!(
+            "Waiting up to {} seconds for players to leave",
+            drain_timeout
+        );
+
+        tokio::select! {
+            _ = wait_for_clients_to_leave(&state) => {},
+            _ = tokio::time::sleep(Duration::from_secs(drain_timeout)) => {},
+            _ = signal::ctrl_c() => {},
+        }
+    }
+
+    telemetry::shutdown();
 
     Ok(())
 }
 
+fn load_config(options: &LaunchOptions) -> Result<Config, anyhow::Error> {
+    let mut config = Config::load(options.config.as_deref())?;
+
+    if let Some(host) = &options.host {
+        config.host = host.clone();
+    }
+
+    if let Some(port) = options.port {
+        config.port = port;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Reloads the config when receiving SIGHUP. Settings that are only read on startup are ignored until restarted.
+#[cfg(unix)]
+async fn reload_config_on_hangup(
+    options: Arc<LaunchOptions>,
+    log_filter_handle: logging::FilterHandle,
+) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(error) => {
+            tracing::error!("Failed to listen for SIGHUP: {}", error);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        let config = match load_config(&options) {
+            Ok(config) => config,
+            Err(error) => {
+                tracing::error!(
+                    "Failed to reload config, keeping the current config: {:?}",
+                    error
+                );
+                continue;
+            }
+        };
+
+        let changes = config.get_restart_required_changes(&config::get());
+
+        if !changes.is_empty() {
+            tracing::warn!(
+                "Changes to {} will not take effect until the server is restarted",
+                changes.join(", ")
+            );
+        }
+
+        if let Err(error) = logging::reload_filter(&log_filter_handle, &config.logging) {
+            tracing::error!("Failed to reload log filter: {:?}", error);
+        }
+
+        config::set(config);
+        tracing::info!("Config reloaded");
+    }
+}
+
+async fn wait_for_clients_to_leave(state: &Arc<Mutex<State>>) {
+    while state.lock().await.get_clients_iter().len() > 0 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
 async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow::Error> {
     let state = state.lock().await;
 
@@ -121,8 +302,10 @@ async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow:
 
     for client in clients {
         let group_players = state
-            .get_clients_in_group(state.get_matchmaking_options(client.0))
-            .count() as u32;
+            .get_matchmaking_options(client.0)
+            .map_or(0, |matchmaking_options| {
+                state.get_clients_in_group(matchmaking_options).count()
+            }) as u32;
 
         // Ignore failed sends
         let _ = client
@@ -136,18 +319,267 @@ async fn broadcast_server_update(state: Arc<Mutex<State>>) -> Result<(), anyhow:
     Ok(())
 }
 
-#[tracing::instrument(skip(socket, state))]
-async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex<State>>) {
+/// Fetches the current names of all connected players and informs everyone about players that changed their name
+async fn refresh_player_names(state: Arc<Mutex<State>>) -> Result<(), anyhow::Error> {
+    steam::cache::remove_expired();
+
+    let steam_ids: Vec<u64> = {
+        let state = state.lock().await;
+        state
+            .get_clients_iter()
+            .map(|(_, client)| client.steam_id)
+            .collect()
+    };
+
+    if steam_ids.is_empty() {
+        return Ok(());
+    }
+
+    // Don't hold the lock while waiting for the steam api
+    let summaries = steam::cache::refresh(&steam_ids).await?;
+
+    let mut state = state.lock().await;
+    let mut renamed = Vec::new();
+
+    for (_, client) in state.get_clients_iter_mut() {
+        if let Some(summary) = summaries.get(&client.steam_id) {
+            if summary.name != client.name {
+                tracing::info!("{} renamed to {}", client, summary.name);
+                client.name = summary.name.clone();
+                renamed.push(PlayerRenamed {
+                    steam_id: client.steam_id,
+                    name: summary.name.clone(),
+                });
+            }
+        }
+    }
+
+    for player in renamed {
+        for (_, client) in state.get_clients_iter() {
+            // Ignore failed sends
+            let _ = client.send(Message::PlayerRenamed(player.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Accepts connections and handles every client in its own task, runs until cancelled
+async fn accept_clients(
+    listener: &TcpListener,
+    proxy_protocol: bool,
+    tls: Option<Arc<Tls>>,
+    state: Arc<Mutex<State>>,
+) {
+    loop {
+        match listener.accept().await {
+            Err(error) => tracing::info!("An error occurred when accepting socket: {}", error),
+            Ok((socket, address)) => {
+                let state = state.clone();
+                let tls_acceptor = tls.as_ref().map(|tls| tls.acceptor());
+                tokio::spawn(async move {
+                    accept_client(socket, address, proxy_protocol, tls_acceptor, state).await;
+                });
+            }
+        }
+    }
+}
+
+/// Resolves the address of the client, checks the connection limit of the address and
+/// sets up TLS if enabled before processing the client
+async fn accept_client(
+    mut socket: TcpStream,
+    address: SocketAddr,
+    proxy_protocol: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: Arc<Mutex<State>>,
+) {
+    // A single deadline covers the PROXY header, the TLS handshake and the first message
+    let handshake_timeout = Duration::from_secs(config::get().limits.handshake_timeout);
+    let handshake_deadline = tokio::time::Instant::now() + handshake_timeout;
+
+    let address = match proxy_protocol {
+        true => {
+            match tokio::time::timeout_at(
+                handshake_deadline,
+                proxy_protocol::read_header(&mut socket),
+            )
+            .await
+            {
+                Ok(Ok(Some(source))) => source,
+                Ok(Ok(None)) => {
+                    // Falling back to the address of the proxy would put every such connection on the same address
+                    tracing::debug!(
+                        "Closing connection from {} without a client address",
+                        address
+                    );
+                    return;
+                }
+                Ok(Err(error)) => {
+                    tracing::debug!("Invalid PROXY protocol header from {}: {}", address, error);
+                    metrics::REJECTED_CONNECTIONS
+                        .with_label_values(&["invalid_proxy_header"])
+                        .inc();
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!(
+                        "Did not receive a PROXY protocol header from {} in time",
+                        address
+                    );
+                    metrics::REJECTED_CONNECTIONS
+                        .with_label_values(&["handshake_timeout"])
+                        .inc();
+                    return;
+                }
+            }
+        }
+        false => address,
+    };
+
+    let _connection_guard = match limits::acquire_connection(address.ip()) {
+        Some(connection_guard) => connection_guard,
+        None => {
+            tracing::debug!("Rejected connection from {}, too many connections", address);
+            metrics::REJECTED_CONNECTIONS
+                .with_label_values(&["ip_limit"])
+                .inc();
+            return;
+        }
+    };
+
+    let stream = match tls_acceptor {
+        Some(tls_acceptor) => {
+            match tokio::time::timeout_at(handshake_deadline, tls_acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => ClientStream::Tls(Box::new(stream)),
+                Ok(Err(error)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", address, error);
+                    metrics::REJECTED_CONNECTIONS
+                        .with_label_values(&["tls_error"])
+                        .inc();
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {} timed out", address);
+                    metrics::REJECTED_CONNECTIONS
+                        .with_label_values(&["handshake_timeout"])
+                        .inc();
+                    return;
+                }
+            }
+        }
+        None => ClientStream::Plain(socket),
+    };
+
+    process_client(stream, address, handshake_deadline, state).await;
+}
+
+/// Sends the notice in a form the negotiated protocol version of the connection supports
+async fn send_notice(
+    messages: &mut Framed<ClientStream, MessagesCodec>,
+    notice: ServerNotice,
+) -> Result<(), anyhow::Error> {
+    let version = messages.codec().version();
+    messages.send(notice.for_version(version)).await
+}
+
+/// Selects the codec of the connection based on its first byte
+async fn open_messages(
+    mut socket: ClientStream,
+) -> Result<Framed<ClientStream, MessagesCodec>, std::io::Error> {
+    let first_byte = socket.read_u8().await?;
+    let mut codec = MessagesCodec::new();
+    let mut read_buf = BytesMut::new();
+
+    if first_byte == codec::JSON_MAGIC_BYTE && config::get().protocol.json_codec {
+        tracing::debug!("Client is using the json codec");
+        codec.set_format(Format::Json);
+    } else {
+        // Anything else is the start of the first frame
+        read_buf.put_u8(first_byte);
+    }
+
+    let mut parts = FramedParts::new::<Message>(socket, codec);
+    parts.read_buf = read_buf;
+    Ok(Framed::from_parts(parts))
+}
+
+#[tracing::instrument(
+    name = "connection",
+    skip(socket, address, handshake_deadline, state),
+    fields(
+        %address,
+        steam_id = tracing::field::Empty,
+        player_name = tracing::field::Empty,
+        protocol_version = tracing::field::Empty,
+        group = tracing::field::Empty
+    )
+)]
+async fn process_client(
+    socket: ClientStream,
+    address: SocketAddr,
+    handshake_deadline: tokio::time::Instant,
+    state: Arc<Mutex<State>>,
+) {
     let (tx, mut rx) = mpsc::unbounded_channel::<MessageType>();
-    let mut messages = MessagesCodec::new().framed(socket);
+    // The handshake records the player on this span from within its own span
+    let connection_span = tracing::Span::current();
+
+    let mut messages =
+        match tokio::time::timeout_at(handshake_deadline, open_messages(socket)).await {
+            Ok(Ok(messages)) => messages,
+            Ok(Err(_)) => return, // Client disconnected
+            Err(_) => {
+                tracing::debug!("Did not receive a handshake in time");
+                metrics::REJECTED_CONNECTIONS
+                    .with_label_values(&["handshake_timeout"])
+                    .inc();
+                return;
+            }
+        };
+
+    let message = match tokio::time::timeout_at(handshake_deadline, messages.next()).await {
+        Ok(message) => message,
+        Err(_) => {
+            tracing::debug!("Did not receive a handshake in time");
+            metrics::REJECTED_CONNECTIONS
+                .with_label_values(&["handshake_timeout"])
+                .inc();
+            return;
+        }
+    };
 
-    match messages.next().await {
+    match message {
         Some(Ok(message)) => match message {
             Message::HandshakeRequest(request) => {
-                if let Err(error) =
-                    handshake::handle_message(&request, tx, &mut messages, &address, &state).await
+                if let Err(error) = handshake::handle_message(
+                    &request,
+                    tx.clone(),
+                    &mut messages,
+                    &address,
+                    &state,
+                    &connection_span,
+                )
+                .await
                 {
                     tracing::warn!("An error occurred while handling handshake: {:?}", error);
+                    disconnect_failed_client(&address, &tx, &state).await;
+                    return;
+                }
+            }
+            Message::ResumeSession(request) => {
+                if let Err(error) = resume_session::handle_message(
+                    &request,
+                    tx.clone(),
+                    &mut messages,
+                    &address,
+                    &state,
+                    &connection_span,
+                )
+                .await
+                {
+                    tracing::warn!("An error occurred while resuming session: {:?}", error);
+                    disconnect_failed_client(&address, &tx, &state).await;
                     return;
                 }
             }
@@ -165,23 +597,57 @@ async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex
         }
     }
 
+    // The client in the state holds the only other sender, so the channel closes once it's removed
+    drop(tx);
+
+    // Cleared when the server closes the connection on purpose
+    let mut can_resume = true;
+
     loop {
         tokio::select! {
-            Some(outbound_message) = rx.recv() => {
-                if let Err(error) = messages.send(outbound_message).await {
-                    tracing::warn!("Failed to send message: {:?}", error);
-                    break; // Client disconnected
+            Some(outbound) = rx.recv() => {
+                metrics::OUTBOUND_QUEUE_DEPTH.dec();
+
+                match outbound {
+                    Outbound::Message(outbound_message) => {
+                        if let Err(error) = messages.send(outbound_message).await {
+                            tracing::warn!("Failed to send message: {:?}", error);
+                            break; // Client disconnected
+                        }
+                    },
+                    Outbound::Disconnect => {
+                        // Disconnected by server
+                        can_resume = false;
+                        break;
+                    }
                 }
             },
             result = messages.next() => match result {
                 Some(Ok(message)) => {
-                    if let Err(error) = handlers::handle_message(&message, &mut messages, &address, &state).await {
+                    if let Err(error) = handlers::handle_message(message, &mut messages, &address, &state).await {
+                        // Errors caused by the client are reported without closing the connection
+                        if let Some(request_failed) = error.downcast_ref::<RequestFailed>() {
+                            tracing::debug!("Rejected message: {}", error);
+                            let notice = ServerNotice::new(NoticeSeverity::Warning, request_failed.0.into());
+
+                            if send_notice(&mut messages, notice).await.is_err() {
+                                break; // Client disconnected
+                            }
+
+                            continue;
+                        }
+
                         tracing::warn!("An error occured when handling message: {:?}", error);
+                        let notice = ServerNotice::new(NoticeSeverity::Error, NoticeCode::InternalError);
+                        let _ = send_notice(&mut messages, notice).await;
+                        can_resume = false;
                         break;
                     }
                 },
                 Some(Err(error)) => {
                     tracing::warn!("An error occured when reading message: {:?}", error);
+                    let notice = ServerNotice::new(NoticeSeverity::Error, NoticeCode::InvalidMessage);
+                    let _ = send_notice(&mut messages, notice).await;
                     break;
                 },
                 None => break // Client disconnected
@@ -190,8 +656,78 @@ async fn process_client(socket: TcpStream, address: SocketAddr, state: Arc<Mutex
         }
     }
 
-    let mut state = state.lock().await;
-    if let Some(client) = state.remove_client(&address) {
-        tracing::info!("{} disconnected", client);
+    disconnect_client(&mut *state.lock().await, address, can_resume, &state);
+
+    // Discard messages that were queued but never sent so they don't count towards the queue depth
+    rx.close();
+    while rx.try_recv().is_ok() {
+        metrics::OUTBOUND_QUEUE_DEPTH.dec();
+    }
+}
+
+/// Keeps the session of a client that lost its connection for the grace period, otherwise removes it right away
+fn disconnect_client(
+    state_guard: &mut State,
+    address: SocketAddr,
+    can_resume: bool,
+    state: &Arc<Mutex<State>>,
+) {
+    let grace_period = config::get().protocol.resume_grace_period;
+    let can_resume = can_resume
+        && grace_period > 0
+        && state_guard
+            .get_client(&address)
+            .is_some_and(|client| client.version >= session::PROTOCOL_VERSION);
+
+    if can_resume {
+        if let Some(client) = state_guard.reserve_client(&address) {
+            tracing::info!(
+                event = "connection_lost",
+                "{} lost connection, keeping session for {} seconds",
+                client,
+                grace_period
+            );
+        }
+
+        tokio::spawn(
+            remove_client_after_grace_period(
+                address,
+                state.clone(),
+                Duration::from_secs(grace_period),
+            )
+            .in_current_span(),
+        );
+    } else if let Some(client) = state_guard.remove_client(&address) {
+        tracing::info!(event = "disconnect", "{} disconnected", client);
+    }
+}
+
+/// Disconnects the client of a connection whose handshake failed after the client was already added
+async fn disconnect_failed_client(
+    address: &SocketAddr,
+    tx: &mpsc::UnboundedSender<MessageType>,
+    state: &Arc<Mutex<State>>,
+) {
+    let mut state_guard = state.lock().await;
+
+    // The address may also belong to a reserved session of an earlier connection
+    if state_guard
+        .get_client(address)
+        .is_some_and(|client| client.is_connected_through(tx))
+    {
+        disconnect_client(&mut state_guard, *address, true, state);
+    }
+}
+
+/// Removes the client if it didn't resume its session within the grace period
+async fn remove_client_after_grace_period(
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    grace_period: Duration,
+) {
+    tokio::time::sleep(grace_period).await;
+
+    if let Some(client) = state.lock().await.remove_reserved_client(&address) {
+        tracing::info!(event = "disconnect", "{} disconnected", client);
     }
 }
@@ -0,0 +1,90 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+lazy_static! {
+    pub static ref CONNECTED_CLIENTS: IntGauge = register_int_gauge!(
+        "jkmp_connected_clients",
+        "Amount of clients that have completed the handshake"
+    )
+    .unwrap();
+    pub static ref GROUPS: IntGaugeVec = register_int_gauge_vec!(
+        "jkmp_groups",
+        "Amount of matchmaking groups by member count",
+        &["size"]
+    )
+    .unwrap();
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "jkmp_messages_received_total",
+        "Messages received from clients by message type",
+        &["message"]
+    )
+    .unwrap();
+    pub static ref MESSAGES_SENT: IntCounterVec = register_int_counter_vec!(
+        "jkmp_messages_sent_total",
+        "Messages sent to clients by message type",
+        &["message"]
+    )
+    .unwrap();
+    pub static ref REJECTED_CONNECTIONS: IntCounterVec = register_int_counter_vec!(
+        "jkmp_rejected_connections_total",
+        "Connections closed before the handshake by reason",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref HANDSHAKES: IntCounterVec = register_int_counter_vec!(
+        "jkmp_handshakes_total",
+        "Handshake attempts by result",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref STEAM_API_DURATION: HistogramVec = register_histogram_vec!(
+        "jkmp_steam_api_request_duration_seconds",
+        "Duration of steam web api requests by endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref STEAM_API_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "jkmp_steam_api_errors_total",
+        "Failed steam web api requests by endpoint",
+        &["endpoint"]
+    )
+    .unwrap();
+    pub static ref ADMISSION_QUEUE_LENGTH: IntGauge = register_int_gauge!(
+        "jkmp_admission_queue_length",
+        "Amount of players waiting for a free slot"
+    )
+    .unwrap();
+    pub static ref OUTBOUND_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "jkmp_outbound_queue_depth",
+        "Total amount of messages queued to be sent to clients"
+    )
+    .unwrap();
+}
+
+/// Upper bounds of the group size buckets reported by the `jkmp_groups` metric
+const GROUP_SIZE_BUCKETS: [(usize, &str); 6] = [
+    (1, "1"),
+    (2, "2"),
+    (4, "3-4"),
+    (8, "5-8"),
+    (16, "9-16"),
+    (usize::MAX, "17+"),
+];
+
+/// Updates the group size distribution from the member counts of every group
+pub fn set_group_sizes(sizes: impl Iterator<Item = usize>) {
+    let mut counts = [0; GROUP_SIZE_BUCKETS.len()];
+
+    for size in sizes {
+        if let Some(index) = GROUP_SIZE_BUCKETS.iter().position(|(max, _)| size <= *max) {
+            counts[index] += 1;
+        }
+    }
+
+    for ((_, label), count) in GROUP_SIZE_BUCKETS.iter().zip(counts) {
+        GROUPS.with_label_values(&[label]).set(count);
+    }
+}
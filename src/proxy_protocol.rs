@@ -0,0 +1,212 @@
+use std::{
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a version 1 header including the line ending
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol version 1 or 2 header from the start of the stream.
+/// Returns the address of the client, or None if the proxy didn't provide one (for example health checks).
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, anyhow::Error> {
+    // Both versions can be told apart by the first 6 bytes, and nothing after the header may be read
+    let mut prefix = [0; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1_header(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2_header(stream).await
+    } else {
+        anyhow::bail!("Missing PROXY protocol header");
+    }
+}
+
+async fn read_v1_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, anyhow::Error> {
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() + V1_PREFIX.len() >= V1_MAX_LENGTH {
+            anyhow::bail!("PROXY protocol header is too long");
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source_ip, _, source_port, _] => {
+            let ip: IpAddr = source_ip.parse().context("Invalid source address")?;
+            let port: u16 = source_port.parse().context("Invalid source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("Invalid PROXY protocol header: {}", line),
+    }
+}
+
+async fn read_v2_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, anyhow::Error> {
+    let mut header = [0; 10];
+    stream.read_exact(&mut header).await?;
+
+    if header[..6] != V2_SIGNATURE[6..] {
+        anyhow::bail!("Invalid PROXY protocol signature");
+    }
+
+    let version_command = header[6];
+    let family = header[7];
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+
+    if version_command >> 4 != 2 {
+        anyhow::bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+
+    // Always read the addresses so the stream is positioned after the header
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    match (version_command & 0x0f, family >> 4) {
+        // LOCAL connections are made by the proxy itself
+        (0, _) => Ok(None),
+        (1, 1) if length >= 12 => {
+            let ip = Ipv4Addr::from(u32::from_be_bytes(addresses[..4].try_into().unwrap()));
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (1, 2) if length >= 36 => {
+            let ip = Ipv6Addr::from(u128::from_be_bytes(addresses[..16].try_into().unwrap()));
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // Unix sockets and unspecified families have no usable address
+        (1, _) => Ok(None),
+        (command, _) => anyhow::bail!("Unsupported PROXY protocol command {}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut header: &[u8]) -> Result<Option<SocketAddr>, anyhow::Error> {
+        read_header(&mut header).await
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_addresses() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 1234 4000\r\n")
+                .await
+                .unwrap(),
+            Some("192.0.2.1:1234".parse().unwrap())
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1234 4000\r\n")
+                .await
+                .unwrap(),
+            Some("[2001:db8::1]:1234".parse().unwrap())
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v1_headers() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 1234\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 invalid 198.51.100.1 1234 4000\r\n")
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 4000\r\n")
+            .await
+            .is_err());
+        assert!(read(&[b"PROXY ".as_ref(), &[b'1'; 200]].concat())
+            .await
+            .is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header() {
+        assert!(read(b"\x05\x00\x00\x00\x00\x00").await.is_err());
+        assert!(read(b"PRO").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_addresses() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend_from_slice(&1234u16.to_be_bytes());
+        ipv4.extend_from_slice(&4000u16.to_be_bytes());
+        assert_eq!(
+            read(&v2_header(1, 0x11, &ipv4)).await.unwrap(),
+            Some("192.0.2.1:1234".parse().unwrap())
+        );
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&1234u16.to_be_bytes());
+        ipv6.extend_from_slice(&4000u16.to_be_bytes());
+        assert_eq!(
+            read(&v2_header(1, 0x21, &ipv6)).await.unwrap(),
+            Some("[2001:db8::1]:1234".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_without_address() {
+        assert_eq!(read(&v2_header(0, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2_header(1, 0x31, &[0; 216])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_headers() {
+        let mut wrong_version = v2_header(1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert!(read(&wrong_version).await.is_err());
+
+        let mut wrong_signature = v2_header(1, 0x11, &[0; 12]);
+        wrong_signature[8] = b'X';
+        assert!(read(&wrong_signature).await.is_err());
+
+        assert!(read(&v2_header(2, 0x11, &[0; 12])).await.is_err());
+
+        // The addresses are cut off
+        let truncated = v2_header(1, 0x11, &[0; 12]);
+        assert!(read(&truncated[..truncated.len() - 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_reading_after_header() {
+        let mut v1: &[u8] = b"PROXY UNKNOWN\r\nrest";
+        read_header(&mut v1).await.unwrap();
+        assert_eq!(v1, b"rest");
+
+        let header = [v2_header(0, 0x00, &[1, 2, 3]), b"rest".to_vec()].concat();
+        let mut v2 = header.as_slice();
+        read_header(&mut v2).await.unwrap();
+        assert_eq!(v2, b"rest");
+    }
+}
@@ -0,0 +1,139 @@
+use serde::Serialize;
+use serde_reflection::{Registry, Tracer, TracerConfig};
+
+use crate::{
+    chat::ChatChannel,
+    client,
+    messages::{
+        CompressionAlgorithm, HandshakeError, Message, NoticeCode, NoticeSeverity,
+        RequestErrorCode, RequestMessage,
+    },
+};
+
+/// Describes how messages are framed and serialized on the wire
+const ENCODING: &str = "bincode (little endian, varint integers), each frame prefixed with its varint encoded length. \
+    From protocol version 11 the length is shifted left by one bit after the handshake, the lowest bit marks deflate compressed frames. \
+    The compression fields at the end of HandshakeRequest, ResumeSession and ProtocolNegotiated are omitted when empty. \
+    Connections that start with the byte 0xff use json payloads and never set frame flags";
+
+/// Machine-readable description of the protocol that clients can generate their serializers from
+#[derive(Serialize)]
+struct Schema {
+    version: u32,
+    min_supported_version: u32,
+    encoding: &'static str,
+    /// Every type reachable from `Message`. Enum variants are listed with their wire index, struct fields in wire order.
+    types: Registry,
+}
+
+/// Returns the protocol schema as pretty printed json
+pub fn dump() -> Result<String, anyhow::Error> {
+    // Tracing errors hold formats that can't be sent across threads, so only their description is kept
+    let types =
+        trace_types().map_err(|error| anyhow::anyhow!("Could not trace messages: {}", error))?;
+
+    let schema = Schema {
+        version: client::VERSION,
+        min_supported_version: client::MIN_SUPPORTED_VERSION,
+        encoding: ENCODING,
+        types,
+    };
+
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+fn trace_types() -> serde_reflection::Result<Registry> {
+    let mut tracer = Tracer::new(TracerConfig::default());
+
+    // Tracing a type only discovers the first variant of the enums it contains,
+    // so the nested enums are traced on their own to include all of their variants
+    tracer.trace_simple_type::<HandshakeError>()?;
+    tracer.trace_simple_type::<ChatChannel>()?;
+    tracer.trace_simple_type::<CompressionAlgorithm>()?;
+    tracer.trace_simple_type::<RequestErrorCode>()?;
+    tracer.trace_simple_type::<NoticeSeverity>()?;
+    tracer.trace_simple_type::<NoticeCode>()?;
+    tracer.trace_simple_type::<RequestMessage>()?;
+    tracer.trace_simple_type::<Message>()?;
+
+    tracer.registry()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use serde_json::Value;
+    use tokio_util::codec::Encoder;
+
+    use super::*;
+    use crate::messages::{Ack, NoticeCode};
+
+    fn schema() -> Value {
+        serde_json::from_str(&dump().unwrap()).unwrap()
+    }
+
+    /// Returns the variant names of the enum ordered by their wire index
+    fn variants(schema: &Value, name: &str) -> Vec<String> {
+        let variants = schema["types"][name]["ENUM"]
+            .as_object()
+            .unwrap_or_else(|| panic!("{} is not an enum", name));
+
+        (0..variants.len())
+            .map(|index| {
+                let variant = variants[&index.to_string()].as_object().unwrap();
+                variant.keys().next().unwrap().clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_versions() {
+        let schema = schema();
+        assert_eq!(schema["version"], client::VERSION);
+        assert_eq!(
+            schema["min_supported_version"],
+            client::MIN_SUPPORTED_VERSION
+        );
+    }
+
+    #[test]
+    fn lists_message_variants_by_wire_index() {
+        let variants = variants(&schema(), "Message");
+        assert_eq!(variants[0], "HandshakeRequest");
+
+        // A small frame starts with a single byte length followed by the variant index
+        let mut frame = BytesMut::new();
+        jkmp_client::codec::MessagesCodec::default()
+            .encode(Message::Ack(Ack { id: 0 }), &mut frame)
+            .unwrap();
+        assert_eq!(variants[frame[1] as usize], "Ack");
+    }
+
+    #[test]
+    fn includes_every_variant_of_nested_enums() {
+        let schema = schema();
+        assert_eq!(
+            variants(&schema, "RequestMessage"),
+            vec![
+                "PositionUpdate",
+                "SetMatchmakingPassword",
+                "IncomingChatMessage"
+            ]
+        );
+        assert_eq!(variants(&schema, "ChatChannel").len(), 3);
+        assert!(variants(&schema, "NoticeCode").contains(&format!("{:?}", NoticeCode::Kicked)));
+    }
+
+    #[test]
+    fn lists_struct_fields_in_wire_order() {
+        let fields = schema()["types"]["ProtocolNegotiated"]["STRUCT"].clone();
+        let names: Vec<&str> = fields
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field.as_object().unwrap().keys().next().unwrap().as_str())
+            .collect();
+
+        assert_eq!(names, vec!["version", "compression"]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::convert::TryInto;
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+pub use jkmp_client::messages::RESUME_SESSION_VERSION as PROTOCOL_VERSION;
+
+const MAC_LENGTH: usize = 32;
+const TOKEN_LENGTH: usize = 16 + MAC_LENGTH;
+
+lazy_static! {
+    // Sessions only live in memory, so tokens don't need to stay valid across restarts
+    static ref SECRET: [u8; 32] = rand::random();
+}
+
+/// Token that allows a client to take over its previous session after losing the connection.
+/// A new token is issued on every handshake and resume, so each token can only be used once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumeToken {
+    pub steam_id: u64,
+    pub nonce: u64,
+}
+
+impl ResumeToken {
+    pub fn new(steam_id: u64) -> Self {
+        Self {
+            steam_id,
+            nonce: rand::random(),
+        }
+    }
+
+    /// Encodes the token as the steam id and nonce followed by their signature
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(TOKEN_LENGTH);
+        bytes.extend_from_slice(&self.steam_id.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+
+        let signature = create_mac(&bytes).finalize().into_bytes();
+        bytes.extend_from_slice(&signature);
+        bytes
+    }
+
+    /// Decodes the token, returns None if it's malformed or the signature doesn't match
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != TOKEN_LENGTH {
+            return None;
+        }
+
+        let (payload, signature) = bytes.split_at(TOKEN_LENGTH - MAC_LENGTH);
+        create_mac(payload).verify_slice(signature).ok()?;
+
+        Some(Self {
+            steam_id: u64::from_le_bytes(payload[..8].try_into().unwrap()),
+            nonce: u64::from_le_bytes(payload[8..].try_into().unwrap()),
+        })
+    }
+}
+
+fn create_mac(payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&*SECRET).expect("Any key length is valid");
+    mac.update(payload);
+    mac
+}
@@ -1,47 +1,180 @@
 use std::{
     collections::{
         hash_map::{Iter, IterMut},
-        HashMap,
+        HashMap, HashSet, VecDeque,
     },
     hash::Hash,
     net::SocketAddr,
 };
 
-use crate::{client::Client, math::Vector2};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+
+use crate::{
+    bans::BanList,
+    chat::{
+        history::ChatHistory,
+        log::{ChatLog, ChatLogEntry},
+        ChatChannel,
+    },
+    client::Client,
+    config,
+    math::Vector2,
+    messages::OutgoingChatMessage,
+    session::ResumeToken,
+};
+
+lazy_static! {
+    // Without a configured key the digests of the same group differ between restarts
+    static ref GROUP_DIGEST_KEY: Vec<u8> = match &config::get().group_digest_key {
+        Some(key) => key.as_bytes().to_vec(),
+        None => rand::random::<[u8; 32]>().to_vec(),
+    };
+}
 
 pub struct State {
     clients: HashMap<SocketAddr, Client>,
+    /// Address of the session of each connected steam account
+    client_addresses: HashMap<u64, SocketAddr>,
+    /// Clients that lost their connection but can still resume their session
+    reserved_clients: HashSet<SocketAddr>,
     matchmaking_map: HashMap<MatchmakingOptions, Vec<SocketAddr>>,
     client_matchmaking_map: HashMap<SocketAddr, MatchmakingOptions>,
+    global_chat_history: ChatHistory,
+    group_chat_history: HashMap<MatchmakingOptions, ChatHistory>,
+    chat_sequence: u64,
+    chat_log: Option<ChatLog>,
+    bans: BanList,
+    maintenance: bool,
+    /// Seconds after which players are told to try again while in maintenance mode
+    maintenance_retry_after: Option<u64>,
+    /// Tickets of players waiting for a free slot, in order of arrival
+    admission_queue: VecDeque<u64>,
+    next_admission_ticket: u64,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            client_addresses: HashMap::new(),
+            reserved_clients: HashSet::new(),
             matchmaking_map: HashMap::new(),
             client_matchmaking_map: HashMap::new(),
+            global_chat_history: ChatHistory::new(),
+            group_chat_history: HashMap::new(),
+            chat_sequence: 0,
+            chat_log: None,
+            bans: BanList::default(),
+            maintenance: false,
+            maintenance_retry_after: None,
+            admission_queue: VecDeque::new(),
+            next_admission_ticket: 0,
         }
     }
 
+    pub fn get_bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn get_bans_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+
+    /// When maintenance mode is enabled new players are not allowed to join
+    pub fn is_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    pub fn get_maintenance_retry_after(&self) -> Option<u64> {
+        self.maintenance_retry_after
+    }
+
+    pub fn set_maintenance(&mut self, maintenance: bool, retry_after: Option<u64>) {
+        self.maintenance = maintenance;
+        self.maintenance_retry_after = retry_after;
+    }
+
+    /// Returns true if admitting the player would exceed the player limit.
+    /// Players that are already connected replace their previous session so they don't need another slot.
+    pub fn is_full(&self, steam_id: u64) -> bool {
+        match config::get().admission.max_players {
+            Some(max_players) => {
+                !self.client_addresses.contains_key(&steam_id) && self.clients.len() >= max_players
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a player to the end of the admission queue and returns their ticket
+    pub fn join_admission_queue(&mut self) -> u64 {
+        let ticket = self.next_admission_ticket;
+        self.next_admission_ticket += 1;
+        self.admission_queue.push_back(ticket);
+        ticket
+    }
+
+    pub fn leave_admission_queue(&mut self, ticket: u64) {
+        self.admission_queue.retain(|other| *other != ticket);
+    }
+
+    /// Returns the amount of players ahead of the ticket in the admission queue
+    pub fn get_admission_queue_position(&self, ticket: u64) -> Option<usize> {
+        self.admission_queue
+            .iter()
+            .position(|other| *other == ticket)
+    }
+
+    pub fn get_admission_queue_len(&self) -> usize {
+        self.admission_queue.len()
+    }
+
+    pub fn set_chat_log(&mut self, chat_log: Option<ChatLog>) {
+        self.chat_log = chat_log;
+    }
+
+    /// Adds the client, replacing any other session of the same steam account.
+    /// Returns the replaced session so it can be disconnected.
+    /// Fails if the address still belongs to the open connection of another client.
     pub fn add_client(
         &mut self,
         address: &SocketAddr,
         client: Client,
         matchmaking_options: MatchmakingOptions,
-    ) -> Option<Client> {
-        match self.clients.insert(*address, client) {
-            Some(client) => Some(client),
-            None => {
-                self.set_matchmaking_options(address, Some(matchmaking_options));
-                None
-            }
+    ) -> Result<Option<Client>, anyhow::Error> {
+        self.take_address(address)?;
+
+        let previous_address = self.client_addresses.insert(client.steam_id, *address);
+        self.clients.insert(*address, client);
+        self.set_matchmaking_options(address, Some(matchmaking_options));
+
+        // Remove the previous session after joining the group so the group chat history is kept if it's the same group
+        Ok(previous_address.and_then(|previous_address| self.remove_client(&previous_address)))
+    }
+
+    /// Frees the address for a new connection by removing a session that is waiting to be resumed from it
+    fn take_address(&mut self, address: &SocketAddr) -> Result<(), anyhow::Error> {
+        if let Some(client) = self.remove_reserved_client(address) {
+            tracing::debug!("Replacing reserved session of {} at {}", client, address);
+        }
+
+        match self.clients.get(address) {
+            Some(client) => anyhow::bail!("{} is still connected from {}", client, address),
+            None => Ok(()),
         }
     }
 
     pub fn remove_client(&mut self, address: &SocketAddr) -> Option<Client> {
+        self.reserved_clients.remove(address);
+
         match self.clients.remove(address) {
             Some(client) => {
+                // The steam id already points to the new session if this session was replaced
+                if self.client_addresses.get(&client.steam_id) == Some(address) {
+                    self.client_addresses.remove(&client.steam_id);
+                }
+
                 self.set_matchmaking_options(address, None);
                 Some(client)
             }
@@ -49,6 +182,68 @@ impl State {
         }
     }
 
+    pub fn get_client_address(&self, steam_id: u64) -> Option<&SocketAddr> {
+        self.client_addresses.get(&steam_id)
+    }
+
+    /// Keeps the client in its group after losing its connection until it resumes its session or is removed
+    pub fn reserve_client(&mut self, address: &SocketAddr) -> Option<&Client> {
+        let client = self.clients.get(address)?;
+        self.reserved_clients.insert(*address);
+        Some(client)
+    }
+
+    /// Removes the client if it's still waiting to resume its session
+    pub fn remove_reserved_client(&mut self, address: &SocketAddr) -> Option<Client> {
+        match self.reserved_clients.contains(address) {
+            true => self.remove_client(address),
+            false => None,
+        }
+    }
+
+    /// Moves the reserved client the token was issued to over to its new address, keeping its place in the group.
+    /// Returns None if there is no session to resume, fails if the address still belongs to the open connection of another client.
+    pub fn resume_client(
+        &mut self,
+        token: &ResumeToken,
+        address: &SocketAddr,
+    ) -> Result<Option<&mut Client>, anyhow::Error> {
+        let previous_address = match self.reserved_clients.iter().find(|previous_address| {
+            self.clients
+                .get(previous_address)
+                .is_some_and(|client| client.resume_token == *token)
+        }) {
+            Some(previous_address) => *previous_address,
+            None => return Ok(None),
+        };
+
+        if previous_address != *address {
+            self.take_address(address)?;
+        }
+
+        self.reserved_clients.remove(&previous_address);
+
+        let client = self.clients.remove(&previous_address).unwrap();
+        self.client_addresses.insert(client.steam_id, *address);
+        self.clients.insert(*address, client);
+
+        let matchmaking_options = self
+            .client_matchmaking_map
+            .remove(&previous_address)
+            .unwrap();
+
+        for group_address in self.matchmaking_map.get_mut(&matchmaking_options).unwrap() {
+            if *group_address == previous_address {
+                *group_address = *address;
+            }
+        }
+
+        self.client_matchmaking_map
+            .insert(*address, matchmaking_options);
+
+        Ok(self.clients.get_mut(address))
+    }
+
     pub fn get_client(&self, address: &SocketAddr) -> Option<&Client> {
         self.clients.get(address)
     }
@@ -57,14 +252,18 @@ impl State {
         self.clients.get_mut(address)
     }
 
-    pub fn get_clients_iter(&self) -> Iter<SocketAddr, Client> {
+    pub fn get_clients_iter(&self) -> Iter<'_, SocketAddr, Client> {
         self.clients.iter()
     }
 
-    pub fn get_clients_iter_mut(&mut self) -> IterMut<SocketAddr, Client> {
+    pub fn get_clients_iter_mut(&mut self) -> IterMut<'_, SocketAddr, Client> {
         self.clients.iter_mut()
     }
 
+    pub fn get_groups_iter(&self) -> Iter<'_, MatchmakingOptions, Vec<SocketAddr>> {
+        self.matchmaking_map.iter()
+    }
+
     pub fn get_clients_in_group(
         &self,
         matchmaking_options: &MatchmakingOptions,
@@ -83,12 +282,13 @@ impl State {
         let mut result = Vec::<&Client>::new();
         let level = get_y_level(position.y);
         let clients = self.get_clients_in_group(matchmaking_options);
+        let proximity_screens = config::get().matchmaking.proximity_screens;
 
         for other in clients {
             let other_level = get_y_level(other.position.y);
 
-            // If the players are within 3 screens from eachother, they are close enough to matchmake
-            if (level - other_level).abs() <= 3 {
+            // If the players are within a few screens from eachother, they are close enough to matchmake
+            if (level - other_level).abs() <= proximity_screens {
                 result.push(other);
             }
         }
@@ -96,8 +296,8 @@ impl State {
         result
     }
 
-    pub fn get_matchmaking_options(&self, address: &SocketAddr) -> &MatchmakingOptions {
-        self.client_matchmaking_map.get(address).unwrap()
+    pub fn get_matchmaking_options(&self, address: &SocketAddr) -> Option<&MatchmakingOptions> {
+        self.client_matchmaking_map.get(address)
     }
 
     pub fn set_matchmaking_options(
@@ -124,6 +324,7 @@ impl State {
 
             if new_group_len == 0 {
                 self.matchmaking_map.remove(previous_options);
+                self.group_chat_history.remove(previous_options);
             }
 
             tracing::info!(
@@ -139,7 +340,7 @@ impl State {
                 let group = self
                     .matchmaking_map
                     .entry(matchmaking_options.clone())
-                    .or_insert_with(Vec::<SocketAddr>::new);
+                    .or_default();
 
                 group.push(*address);
 
@@ -159,6 +360,60 @@ impl State {
             }
         }
     }
+
+    /// Adds a chat message to the history of the channel it was sent in and writes it to the chat log
+    pub fn add_chat_message(
+        &mut self,
+        matchmaking_options: &MatchmakingOptions,
+        message: OutgoingChatMessage,
+    ) {
+        if let Some(chat_log) = &self.chat_log {
+            chat_log.write(ChatLogEntry {
+                timestamp: chrono::Utc::now(),
+                channel: message.channel,
+                group: matchmaking_options.digest(),
+                sender_id: message.sender_id,
+                sender_name: message.sender_name.clone(),
+                message: message.message.clone(),
+            });
+        }
+
+        let history = match message.channel {
+            ChatChannel::Global => &mut self.global_chat_history,
+            ChatChannel::Group => self
+                .group_chat_history
+                .entry(matchmaking_options.clone())
+                .or_insert_with(ChatHistory::new),
+            ChatChannel::Local => return,
+        };
+
+        self.chat_sequence += 1;
+        history.push(
+            self.chat_sequence,
+            message,
+            config::get().chat.history_length,
+        );
+    }
+
+    /// Returns the global and group chat history visible to a member of the given group, oldest message first
+    pub fn get_chat_history(
+        &self,
+        matchmaking_options: &MatchmakingOptions,
+    ) -> Vec<&OutgoingChatMessage> {
+        let mut entries: Vec<_> = self
+            .global_chat_history
+            .iter()
+            .chain(
+                self.group_chat_history
+                    .get(matchmaking_options)
+                    .into_iter()
+                    .flat_map(|history| history.iter()),
+            )
+            .collect();
+
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| &entry.message).collect()
+    }
 }
 
 #[inline]
@@ -184,4 +439,159 @@ impl MatchmakingOptions {
             level_name,
         }
     }
+
+    /// Returns a short hex digest identifying the group without revealing the password.
+    /// The digest is keyed with `group_digest_key` so passwords can't be guessed from logs.
+    pub fn digest(&self) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&GROUP_DIGEST_KEY)
+            .expect("HMAC can take a key of any size");
+        mac.update(&(self.level_name.len() as u64).to_le_bytes());
+        mac.update(self.level_name.as_bytes());
+
+        // Tagged so that no password and an empty password are different groups
+        match &self.password {
+            Some(password) => {
+                mac.update(&[1]);
+                mac.update(password.as_bytes());
+            }
+            None => mac.update(&[0]),
+        }
+
+        hex::encode(&mac.finalize().into_bytes()[..8])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::sync::mpsc;
+
+    use super::{MatchmakingOptions, State};
+    use crate::{client::Client, math::Vector2};
+
+    fn client(steam_id: u64) -> Client {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let position = Vector2 { x: 0.0, y: 0.0 };
+        Client::new(tx, steam_id, steam_id.to_string(), position, 0)
+    }
+
+    fn options() -> MatchmakingOptions {
+        MatchmakingOptions::new(None, "level".to_string())
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    fn digest(password: Option<&str>, level_name: &str) -> String {
+        MatchmakingOptions::new(password.map(str::to_string), level_name.to_string()).digest()
+    }
+
+    #[test]
+    fn digest_is_stable() {
+        assert_eq!(
+            digest(Some("secret"), "level"),
+            digest(Some("secret"), "level")
+        );
+    }
+
+    #[test]
+    fn digest_differs_between_groups() {
+        assert_ne!(
+            digest(Some("secret"), "level"),
+            digest(Some("other"), "level")
+        );
+        assert_ne!(
+            digest(Some("secret"), "level"),
+            digest(Some("secret"), "other")
+        );
+    }
+
+    #[test]
+    fn digest_distinguishes_no_password_from_empty_password() {
+        assert_ne!(digest(None, "level"), digest(Some(""), "level"));
+    }
+
+    #[test]
+    fn digest_is_not_ambiguous_between_level_and_password() {
+        assert_ne!(digest(Some("b"), "a"), digest(Some(""), "a\u{1}b"));
+    }
+
+    #[test]
+    fn add_client_replaces_previous_session() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+
+        let previous = state.add_client(&address(2), client(1), options()).unwrap();
+
+        assert_eq!(previous.map(|client| client.steam_id), Some(1));
+        assert!(state.get_client(&address(1)).is_none());
+        assert_eq!(state.get_client_address(1), Some(&address(2)));
+    }
+
+    #[test]
+    fn add_client_rejects_address_of_connected_client() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+
+        assert!(state.add_client(&address(1), client(2), options()).is_err());
+        assert_eq!(state.get_client(&address(1)).unwrap().steam_id, 1);
+        assert_eq!(state.get_client_address(2), None);
+    }
+
+    #[test]
+    fn add_client_replaces_reserved_client_at_address() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+        state.reserve_client(&address(1));
+
+        assert!(state
+            .add_client(&address(1), client(2), options())
+            .unwrap()
+            .is_none());
+        assert_eq!(state.get_client(&address(1)).unwrap().steam_id, 2);
+        assert_eq!(state.get_client_address(1), None);
+
+        // The grace period of the replaced session doesn't remove the new client
+        assert!(state.remove_reserved_client(&address(1)).is_none());
+    }
+
+    #[test]
+    fn resume_client_moves_reserved_client() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+        let token = state.reserve_client(&address(1)).unwrap().resume_token;
+
+        let resumed = state.resume_client(&token, &address(2)).unwrap();
+
+        assert_eq!(resumed.map(|client| client.steam_id), Some(1));
+        assert!(state.get_client(&address(1)).is_none());
+        assert!(state.get_matchmaking_options(&address(2)) == Some(&options()));
+        assert_eq!(state.get_clients_in_group(&options()).count(), 1);
+    }
+
+    #[test]
+    fn resume_client_rejects_address_of_connected_client() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+        state.add_client(&address(2), client(2), options()).unwrap();
+        let token = state.reserve_client(&address(1)).unwrap().resume_token;
+
+        assert!(state.resume_client(&token, &address(2)).is_err());
+        assert_eq!(state.get_client(&address(2)).unwrap().steam_id, 2);
+
+        // The session can still be resumed from another address
+        assert!(state.resume_client(&token, &address(3)).unwrap().is_some());
+    }
+
+    #[test]
+    fn resume_client_without_session() {
+        let mut state = State::new();
+        state.add_client(&address(1), client(1), options()).unwrap();
+        let token = state.get_client(&address(1)).unwrap().resume_token;
+
+        // Only reserved sessions can be resumed
+        assert!(state.resume_client(&token, &address(2)).unwrap().is_none());
+    }
 }
@@ -1,14 +1,61 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, time::Duration};
 
 use anyhow::Context;
+use lazy_static::lazy_static;
+use rand::Rng;
 use reqwest::{RequestBuilder, StatusCode};
 use serde::{self, Deserialize};
+use tokio::sync::Semaphore;
 
-const APP_ID: u32 = 1061090;
-const URL_AUTH_USER_TICKET: &str =
-    "https://api.steampowered.com/ISteamUserAuth/AuthenticateUserTicket/v1/";
-const URL_GET_PLAYER_SUMMARIES: &str =
-    "https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v2/";
+use crate::{config, metrics};
+
+pub mod cache;
+mod circuit_breaker;
+
+use circuit_breaker::CircuitBreaker;
+
+const PATH_AUTH_USER_TICKET: &str = "/ISteamUserAuth/AuthenticateUserTicket/v1/";
+const PATH_GET_PLAYER_SUMMARIES: &str = "/ISteamUser/GetPlayerSummaries/v2/";
+
+/// Maximum amount of steam ids that can be requested at once from GetPlayerSummaries
+pub const MAX_PLAYER_SUMMARIES_PER_REQUEST: usize = 100;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = create_client().expect("Failed to create http client");
+    static ref VERIFICATIONS: Semaphore =
+        Semaphore::new(config::get().limits.max_concurrent_verifications);
+    static ref CIRCUIT_BREAKER: CircuitBreaker = {
+        let config = config::get();
+        CircuitBreaker::new(
+            config.steam.circuit_breaker_threshold,
+            Duration::from_secs(config.steam.circuit_breaker_cooldown),
+        )
+    };
+}
+
+/// Returned when the steam api can't be reached, either because requests failed or the circuit breaker is open
+#[derive(Debug)]
+pub struct SteamUnavailable;
+
+impl Display for SteamUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Steam api is unavailable")
+    }
+}
+
+impl std::error::Error for SteamUnavailable {}
+
+/// Returned when too many auth tickets are already being verified
+#[derive(Debug)]
+pub struct TooManyVerifications;
+
+impl Display for TooManyVerifications {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many auth tickets are being verified")
+    }
+}
+
+impl std::error::Error for TooManyVerifications {}
 
 #[derive(Debug, Deserialize)]
 struct ApiResponse<T> {
@@ -43,15 +90,10 @@ struct ErrorResponse {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct AuthenticateUserTicketParams {
-    result: String,
     #[serde(rename = "steamid")]
     steam_id: String,
     #[serde(rename = "ownersteamid")]
     owner_steam_id: String,
-    #[serde(rename = "vacbanned")]
-    vac_banned: bool,
-    #[serde(rename = "publisherbanned")]
-    publisher_banned: bool,
 }
 
 #[derive(Debug)]
@@ -87,7 +129,7 @@ struct ReqPlayerSummary {
     name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct PlayerSummary {
     pub steam_id: u64,
     pub name: String,
@@ -100,15 +142,18 @@ impl PlayerSummary {
 }
 
 /// Verifies the user auth ticket and if successful returns the user steam id and owner id (owner id is different if the game is family shared)
+#[tracing::instrument(skip_all)]
 pub async fn verify_user_auth_ticket(ticket: &[u8]) -> Result<UserSteamId, anyhow::Error> {
-    let client = create_client()?;
-    let ticket_str: String = hex::encode(&ticket);
+    // Limits how fast a flood of handshakes can use up the api quota
+    let _permit = VERIFICATIONS
+        .try_acquire()
+        .map_err(|_| TooManyVerifications)?;
+    let ticket_str: String = hex::encode(ticket);
 
-    let response = create_request(reqwest::Method::GET, &client, URL_AUTH_USER_TICKET)?
-        .query(&[("appid", APP_ID)])
-        .query(&[("ticket", &ticket_str)])
-        .send()
-        .await?;
+    let request = create_request(reqwest::Method::GET, PATH_AUTH_USER_TICKET)?
+        .query(&[("appid", config::get().steam.app_id)])
+        .query(&[("ticket", &ticket_str)]);
+    let response = send_request("authenticate_user_ticket", request).await?;
 
     match response.status() {
         StatusCode::OK => {
@@ -138,40 +183,102 @@ pub async fn verify_user_auth_ticket(ticket: &[u8]) -> Result<UserSteamId, anyho
 }
 
 fn get_steam_api_key() -> Result<String, anyhow::Error> {
-    let steam_api_key = std::env::var("STEAM_API_KEY")
-        .context("Could not find STEAM_API_KEY environment variable")?;
+    let steam_api_key = config::get()
+        .steam
+        .api_key
+        .clone()
+        .context("Steam api key is not configured")?;
     Ok(steam_api_key)
 }
 
 fn create_client() -> Result<reqwest::Client, reqwest::Error> {
+    let config = config::get();
     let client = reqwest::Client::builder()
         .user_agent("JKMP_BACKEND")
+        .connect_timeout(Duration::from_millis(config.steam.connect_timeout_ms))
+        .timeout(Duration::from_millis(config.steam.request_timeout_ms))
         .build()?;
     Ok(client)
 }
 
-fn create_request(
-    method: reqwest::Method,
-    client: &reqwest::Client,
-    url: &str,
-) -> Result<RequestBuilder, anyhow::Error> {
+fn create_request(method: reqwest::Method, path: &str) -> Result<RequestBuilder, anyhow::Error> {
     let steam_api_key = get_steam_api_key()?;
-    Ok(client
+    let url = format!("{}{}", config::get().steam.api_url, path);
+    Ok(CLIENT
         .request(method, url)
         .query(&[("key", &steam_api_key)]))
 }
 
+/// Sends the request, retrying server errors and network errors with a jittered exponential backoff.
+/// Fails with `SteamUnavailable` without sending anything while the circuit breaker is open.
+async fn send_request(
+    endpoint: &str,
+    request: RequestBuilder,
+) -> Result<reqwest::Response, anyhow::Error> {
+    if !CIRCUIT_BREAKER.allow_request() {
+        return Err(SteamUnavailable.into());
+    }
+
+    let config = config::get();
+    let mut attempt = 0;
+
+    loop {
+        let timer = metrics::STEAM_API_DURATION
+            .with_label_values(&[endpoint])
+            .start_timer();
+        let result = request
+            .try_clone()
+            .context("Request can not be retried")?
+            .send()
+            .await;
+        timer.observe_duration();
+
+        match result {
+            Ok(response) if !response.status().is_server_error() => {
+                CIRCUIT_BREAKER.record_success();
+                return Ok(response);
+            }
+            Ok(response) => tracing::warn!("{} failed with {}", endpoint, response.status()),
+            // The url is removed since it contains the api key
+            Err(error) => tracing::warn!("{} failed: {}", endpoint, error.without_url()),
+        }
+
+        metrics::STEAM_API_ERRORS
+            .with_label_values(&[endpoint])
+            .inc();
+
+        if attempt >= config.steam.max_retries {
+            CIRCUIT_BREAKER.record_failure();
+            return Err(SteamUnavailable.into());
+        }
+
+        tokio::time::sleep(get_retry_delay(attempt, config.steam.retry_base_delay_ms)).await;
+        attempt += 1;
+    }
+}
+
+/// Returns a random delay between zero and the exponentially increasing maximum delay for the attempt
+fn get_retry_delay(attempt: u32, base_delay_ms: u64) -> Duration {
+    let max_delay_ms = base_delay_ms.saturating_mul(1 << attempt.min(16));
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay_ms))
+}
+
+/// Returns false while requests to the steam api are failing
+pub fn is_available() -> bool {
+    !CIRCUIT_BREAKER.is_open()
+}
+
+#[tracing::instrument(skip_all, fields(count = user_ids.len()))]
 pub async fn get_player_summaries(
     user_ids: Vec<u64>,
 ) -> Result<HashMap<u64, PlayerSummary>, anyhow::Error> {
-    let client = create_client()?;
-    let mut builder = create_request(reqwest::Method::GET, &client, URL_GET_PLAYER_SUMMARIES)?;
+    let mut builder = create_request(reqwest::Method::GET, PATH_GET_PLAYER_SUMMARIES)?;
 
-    for user_id in user_ids {
-        builder = builder.query(&[("steamids", user_id.to_string())]);
-    }
+    // The api expects a comma separated list of up to 100 steam ids
+    let steam_ids: Vec<String> = user_ids.iter().map(|id| id.to_string()).collect();
+    builder = builder.query(&[("steamids", steam_ids.join(","))]);
 
-    let response = builder.send().await?;
+    let response = send_request("get_player_summaries", builder).await?;
 
     match response.status() {
         StatusCode::OK => {
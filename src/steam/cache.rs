@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+
+use super::{PlayerSummary, MAX_PLAYER_SUMMARIES_PER_REQUEST};
+use crate::config;
+
+lazy_static! {
+    static ref PLAYER_SUMMARIES: Mutex<HashMap<u64, CachedPlayerSummary>> =
+        Mutex::new(HashMap::new());
+}
+
+struct CachedPlayerSummary {
+    summary: PlayerSummary,
+    fetched_at: Instant,
+}
+
+/// Returns the cached player summary if it hasn't expired, otherwise fetches it from the steam api
+pub async fn get_player_summary(steam_id: u64) -> Result<PlayerSummary, anyhow::Error> {
+    let ttl = get_ttl();
+
+    if let Some(cached) = PLAYER_SUMMARIES.lock().unwrap().get(&steam_id) {
+        if cached.fetched_at.elapsed() < ttl {
+            return Ok(cached.summary.clone());
+        }
+    }
+
+    let mut summaries = refresh(&[steam_id]).await?;
+    summaries
+        .remove(&steam_id)
+        .context("Could not get user info from steam")
+}
+
+/// Fetches the player summaries from the steam api regardless of whether they're cached, and updates the cache
+pub async fn refresh(steam_ids: &[u64]) -> Result<HashMap<u64, PlayerSummary>, anyhow::Error> {
+    let mut result = HashMap::new();
+
+    for chunk in steam_ids.chunks(MAX_PLAYER_SUMMARIES_PER_REQUEST) {
+        let summaries = super::get_player_summaries(chunk.to_vec()).await?;
+        let fetched_at = Instant::now();
+        let mut cache = PLAYER_SUMMARIES.lock().unwrap();
+
+        for summary in summaries.into_values() {
+            cache.insert(
+                summary.steam_id,
+                CachedPlayerSummary {
+                    summary: summary.clone(),
+                    fetched_at,
+                },
+            );
+            result.insert(summary.steam_id, summary);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Removes expired entries so players that left don't stay in the cache forever
+pub fn remove_expired() {
+    let ttl = get_ttl();
+
+    PLAYER_SUMMARIES
+        .lock()
+        .unwrap()
+        .retain(|_, cached| cached.fetched_at.elapsed() < ttl);
+}
+
+fn get_ttl() -> Duration {
+    Duration::from_secs(config::get().steam.player_summary_ttl)
+}
@@ -0,0 +1,188 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Stops requests from being sent after too many consecutive failures, until a cooldown has passed
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+enum CircuitState {
+    Closed {
+        consecutive_failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The cooldown has passed and a single trial request is in flight.
+    /// A trial that never records an outcome, for example because it was cancelled, expires after another cooldown.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(CircuitState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Returns whether a request may be sent. After the cooldown only one trial request is let through.
+    pub fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } | CircuitState::HalfOpen { until }
+                if Instant::now() >= until =>
+            {
+                *state = CircuitState::HalfOpen {
+                    until: Instant::now() + self.cooldown,
+                };
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        let consecutive_failures = match *state {
+            CircuitState::Closed {
+                consecutive_failures,
+            } => consecutive_failures + 1,
+            // A failed trial request opens the circuit again immediately
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => self.failure_threshold,
+        };
+
+        if consecutive_failures >= self.failure_threshold {
+            if !matches!(*state, CircuitState::Open { .. }) {
+                tracing::warn!(
+                    "Circuit opened after {} consecutive failures",
+                    consecutive_failures
+                );
+            }
+
+            *state = CircuitState::Open {
+                until: Instant::now() + self.cooldown,
+            };
+        } else {
+            *state = CircuitState::Closed {
+                consecutive_failures,
+            };
+        }
+    }
+
+    /// Returns true while requests are being rejected
+    pub fn is_open(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            CircuitState::Closed { .. } => false,
+            CircuitState::Open { until } => Instant::now() < until,
+            CircuitState::HalfOpen { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn open_breaker() -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow_request());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+
+        assert!(!breaker.is_open());
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn allows_single_trial_after_cooldown() {
+        let breaker = open_breaker();
+        sleep(COOLDOWN);
+
+        assert!(!breaker.is_open());
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn successful_trial_closes_circuit() {
+        let breaker = open_breaker();
+        sleep(COOLDOWN);
+
+        assert!(breaker.allow_request());
+        breaker.record_success();
+
+        assert!(breaker.allow_request());
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn failed_trial_opens_circuit_again() {
+        let breaker = open_breaker();
+        sleep(COOLDOWN);
+
+        assert!(breaker.allow_request());
+        breaker.record_failure();
+
+        assert!(breaker.is_open());
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn abandoned_trial_expires() {
+        let breaker = open_breaker();
+        sleep(COOLDOWN);
+
+        // The trial never records an outcome
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+
+        sleep(COOLDOWN);
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+    }
+}
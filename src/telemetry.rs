@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    runtime,
+    trace::{BatchConfigBuilder, BatchSpanProcessor, Sampler, Tracer, TracerProvider},
+    Resource,
+};
+
+use crate::config::TelemetryConfig;
+
+/// Creates a tracer exporting spans over OTLP/HTTP, or returns None if no endpoint is configured
+pub fn init_tracer(config: &TelemetryConfig) -> Result<Option<Tracer>, anyhow::Error> {
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    let batch_config = BatchConfigBuilder::default()
+        .with_scheduled_delay(Duration::from_millis(config.export_delay_ms))
+        .build();
+    let processor = BatchSpanProcessor::builder(exporter, runtime::Tokio)
+        .with_batch_config(batch_config)
+        .build();
+
+    let provider = TracerProvider::builder()
+        .with_span_processor(processor)
+        .with_sampler(Sampler::TraceIdRatioBased(config.sample_ratio))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]))
+        .build();
+
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+
+    Ok(Some(tracer))
+}
+
+/// Flushes spans that haven't been exported yet
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,154 @@
+use jkmp_client::Event;
+
+use super::{handshake_request, run, TestServer};
+use crate::{
+    chat::ChatChannel,
+    client,
+    messages::{
+        HandshakeRequest, IncomingChatMessage, Message, NoticeCode, RequestErrorCode,
+        RequestMessage,
+    },
+};
+
+#[test]
+fn group_chat_only_reaches_the_group() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+        let mut second = server.join(2).await;
+        let mut other = server
+            .join_with(HandshakeRequest {
+                level_name: "Other level".to_string(),
+                ..handshake_request(3)
+            })
+            .await;
+
+        let sender_id = first.steam_id;
+        first
+            .client
+            .send_chat_message(ChatChannel::Group, "Hello group".to_string())
+            .await
+            .unwrap();
+
+        for player in [&mut first, &mut second] {
+            let message = player.next_chat_message().await;
+            assert!(matches!(message.channel, ChatChannel::Group));
+            assert_eq!(message.sender_id, Some(sender_id));
+            assert_eq!(message.message, "Hello group");
+        }
+
+        // Messages are delivered in order, so the other group would have received the group message first
+        first
+            .client
+            .send_chat_message(ChatChannel::Global, "Hello everyone".to_string())
+            .await
+            .unwrap();
+
+        for player in [&mut first, &mut second, &mut other] {
+            let message = player.next_chat_message().await;
+            assert!(matches!(message.channel, ChatChannel::Global));
+            assert_eq!(message.message, "Hello everyone");
+        }
+    });
+}
+
+#[test]
+fn chat_history_is_replayed_on_join() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+
+        first
+            .client
+            .send_chat_message(ChatChannel::Global, "Hello".to_string())
+            .await
+            .unwrap();
+        first.next_chat_message().await;
+
+        let second = server.join(2).await;
+        assert_eq!(second.history.len(), 1);
+        assert_eq!(second.history[0].sender_id, Some(first.steam_id));
+        assert_eq!(second.history[0].message, "Hello");
+        assert!(second.welcome.is_some());
+    });
+}
+
+#[test]
+fn chat_message_is_trimmed_and_sent_with_player_name() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut player = server.join(1).await;
+
+        player
+            .client
+            .send_chat_message(ChatChannel::Global, "  Hello  ".to_string())
+            .await
+            .unwrap();
+
+        let message = player.next_chat_message().await;
+        assert_eq!(message.message, "Hello");
+        assert_eq!(message.sender_name, Some(super::fake_steam::player_name(1)));
+    });
+}
+
+#[test]
+fn local_chat_is_rejected_without_disconnecting() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut player = server.join(1).await;
+
+        let id = player
+            .client
+            .send_request(RequestMessage::IncomingChatMessage(IncomingChatMessage {
+                channel: ChatChannel::Local,
+                message: "Hello".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        match player.next_event().await {
+            Event::RequestError(error) => {
+                assert_eq!(error.id, id);
+                assert_eq!(error.code, RequestErrorCode::InvalidChannel);
+            }
+            event => panic!("Expected a request error, received {:?}", event),
+        }
+
+        // The connection is still usable after the failed request
+        player
+            .request(RequestMessage::IncomingChatMessage(IncomingChatMessage {
+                channel: ChatChannel::Global,
+                message: "Hello".to_string(),
+            }))
+            .await;
+        assert_eq!(server.state().await.get_clients_iter().len(), 1);
+    });
+}
+
+#[test]
+fn rejected_message_is_reported_as_chat_to_clients_without_notices() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut player = server
+            .join_with(HandshakeRequest {
+                version: client::MIN_SUPPORTED_VERSION,
+                ..handshake_request(1)
+            })
+            .await;
+
+        // Requests are not supported either, so the message is sent on its own
+        player
+            .client
+            .send(Message::IncomingChatMessage(IncomingChatMessage {
+                channel: ChatChannel::Local,
+                message: "Hello".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let message = player.next_chat_message().await;
+        assert_eq!(message.sender_id, None);
+        assert_eq!(message.message, NoticeCode::InvalidChannel.description());
+        assert_eq!(server.state().await.get_clients_iter().len(), 1);
+    });
+}
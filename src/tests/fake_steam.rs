@@ -0,0 +1,96 @@
+use std::{
+    convert::{Infallible, TryInto},
+    net::SocketAddr,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use serde_json::json;
+
+/// Ticket that the fake api accepts for the steam id
+pub fn ticket(steam_id: u64) -> Vec<u8> {
+    steam_id.to_le_bytes().to_vec()
+}
+
+/// Name that the fake api returns for the steam id
+pub fn player_name(steam_id: u64) -> String {
+    format!("Player {}", steam_id)
+}
+
+/// Starts a fake steam web api that accepts tickets created by `ticket`, returns its base url
+pub async fn start() -> Result<String, anyhow::Error> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request| async move {
+            Ok::<_, Infallible>(handle_request(request))
+        }))
+    });
+
+    // Connections are not kept alive so the client never reuses a connection of a finished test
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?
+        .http1_keepalive(false)
+        .serve(make_service);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    Ok(url)
+}
+
+fn handle_request(request: Request<Body>) -> Response<Body> {
+    let url = reqwest::Url::parse(&format!("http://localhost{}", request.uri())).unwrap();
+    let parameter = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default()
+    };
+
+    let response = match request.uri().path() {
+        "/ISteamUserAuth/AuthenticateUserTicket/v1/" => {
+            match hex::decode(parameter("ticket"))
+                .ok()
+                .and_then(|ticket| decode_ticket(&ticket))
+            {
+                Some(steam_id) => json!({
+                    "response": {
+                        "params": {
+                            "result": "OK",
+                            "steamid": steam_id.to_string(),
+                            "ownersteamid": steam_id.to_string(),
+                            "vacbanned": false,
+                            "publisherbanned": false,
+                        }
+                    }
+                }),
+                None => json!({
+                    "response": {
+                        "error": { "errorcode": 101, "errordesc": "Invalid ticket" }
+                    }
+                }),
+            }
+        }
+        "/ISteamUser/GetPlayerSummaries/v2/" => {
+            let players: Vec<_> = parameter("steamids")
+                .split(',')
+                .filter_map(|steam_id| steam_id.parse::<u64>().ok())
+                .map(|steam_id| {
+                    json!({
+                        "steamid": steam_id.to_string(),
+                        "personaname": player_name(steam_id),
+                    })
+                })
+                .collect();
+
+            json!({ "response": { "players": players } })
+        }
+        _ => return Response::builder().status(404).body(Body::empty()).unwrap(),
+    };
+
+    Response::new(Body::from(response.to_string()))
+}
+
+/// Returns the steam id of a ticket created by `ticket`
+pub fn decode_ticket(ticket: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(ticket.try_into().ok()?))
+}
@@ -0,0 +1,156 @@
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::Framed;
+
+use super::{handshake_request, run, TestServer};
+use crate::{
+    codec::MessagesCodec,
+    handlers,
+    math::Vector2,
+    messages::{HandshakeRequest, RequestMessage, SetMatchmakingPassword},
+    state::MatchmakingOptions,
+    stream::ClientStream,
+};
+
+const START: Vector2 = Vector2 { x: 0.0, y: 0.0 };
+
+fn set_password(password: Option<&str>) -> RequestMessage {
+    RequestMessage::SetMatchmakingPassword(SetMatchmakingPassword {
+        password: password.map(str::to_string),
+    })
+}
+
+#[test]
+fn players_in_the_same_group_are_matched() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+        let mut second = server.join(2).await;
+
+        assert_eq!(first.move_to(START).await, vec![2]);
+        assert_eq!(second.move_to(START).await, vec![1]);
+    });
+}
+
+#[test]
+fn players_in_different_levels_are_not_matched() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+        let _second = server
+            .join_with(HandshakeRequest {
+                level_name: "Other level".to_string(),
+                ..handshake_request(2)
+            })
+            .await;
+
+        assert!(first.move_to(START).await.is_empty());
+        assert_eq!(server.state().await.get_groups_iter().len(), 2);
+    });
+}
+
+#[test]
+fn leaving_player_is_removed_from_group() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+        let second = server.join(2).await;
+
+        assert_eq!(first.move_to(START).await, vec![2]);
+
+        drop(second);
+        server
+            .wait_until(|state| state.get_client_address(2).is_none())
+            .await;
+
+        assert!(first.move_to(START).await.is_empty());
+    });
+}
+
+#[test]
+fn password_switch_moves_player_to_other_group() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut first = server.join(1).await;
+        let mut second = server.join(2).await;
+
+        assert!(second
+            .request(set_password(Some("secret")))
+            .await
+            .is_empty());
+        assert!(first.move_to(START).await.is_empty());
+
+        let mut third = server
+            .join_with(HandshakeRequest {
+                matchmaking_password: Some("secret".to_string()),
+                ..handshake_request(3)
+            })
+            .await;
+        assert_eq!(third.move_to(START).await, vec![2]);
+
+        // Switching back reports the players of the original group right away
+        let events = second.request(set_password(None)).await;
+        assert!(matches!(
+            events.as_slice(),
+            [jkmp_client::Event::NearbyClients(message)] if message.client_ids == vec![1]
+        ));
+        assert!(third.move_to(START).await.is_empty());
+    });
+}
+
+#[test]
+fn disconnect_cleans_up_state() {
+    run(async {
+        let server = TestServer::start().await;
+        let player = server.join(1).await;
+
+        drop(player);
+        server
+            .wait_until(|state| {
+                state.get_clients_iter().len() == 0
+                    && state.get_client_address(1).is_none()
+                    && state.get_groups_iter().len() == 0
+            })
+            .await;
+    });
+}
+
+#[test]
+fn replaced_session_can_not_change_password() {
+    run(async {
+        let server = TestServer::start().await;
+        let _previous = server.join(1).await;
+        let previous_address = *server.state().await.get_client_address(1).unwrap();
+        let _current = server.join(1).await;
+
+        // The connection of the replaced session is closed asynchronously, so it can still send a
+        // message after it was removed. It's handled directly since the race can't be forced otherwise.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut messages = Framed::new(ClientStream::Plain(socket), MessagesCodec::new());
+
+        let error = handlers::set_matchmaking_password::handle_message(
+            &SetMatchmakingPassword {
+                password: Some("secret".to_string()),
+            },
+            &mut messages,
+            &previous_address,
+            &server.state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Client not found");
+
+        let state = server.state().await;
+        let address = *state.get_client_address(1).unwrap();
+        assert_ne!(address, previous_address);
+        assert!(state.get_client(&previous_address).is_none());
+        assert!(
+            state.get_matchmaking_options(&address)
+                == Some(&MatchmakingOptions::new(None, "Level".to_string()))
+        );
+        assert_eq!(state.get_groups_iter().len(), 1);
+    });
+}
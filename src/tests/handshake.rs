@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use jkmp_client::HandshakeRejected;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{fake_steam, handshake_request, run, TestServer};
+use crate::{
+    client, codec, config,
+    messages::{CompressionAlgorithm, HandshakeError, HandshakeRequest},
+};
+
+/// Sends the handshake and returns the error the server rejected it with
+async fn expect_rejection(
+    server: &TestServer,
+    request: HandshakeRequest,
+) -> Option<HandshakeError> {
+    let mut client = server.connect().await;
+    let error = client
+        .handshake(request)
+        .await
+        .expect_err("Handshake should be rejected");
+
+    error
+        .downcast_ref::<HandshakeRejected>()
+        .expect("Handshake failed without a response")
+        .error
+}
+
+#[test]
+fn handshake_adds_client_to_state() {
+    run(async {
+        let server = TestServer::start().await;
+        let player = server.join(1).await;
+
+        assert_eq!(player.version, client::VERSION);
+        assert_eq!(
+            player.welcome.unwrap().message,
+            "Welcome! There are currently no other players online."
+        );
+
+        let status = player.status.unwrap();
+        assert_eq!((status.total_players, status.group_players), (1, 1));
+
+        let state = server.state().await;
+        let address = state.get_client_address(1).expect("Client was not added");
+        let client = state.get_client(address).unwrap();
+        assert_eq!(client.name, fake_steam::player_name(1));
+        assert_eq!(
+            state.get_matchmaking_options(address).unwrap().level_name,
+            "Level"
+        );
+    });
+}
+
+#[test]
+fn welcome_message_counts_other_players() {
+    run(async {
+        let server = TestServer::start().await;
+        let _first = server.join(1).await;
+        let _second = server
+            .join_with(HandshakeRequest {
+                level_name: "Other level".to_string(),
+                ..handshake_request(2)
+            })
+            .await;
+        let third = server.join(3).await;
+
+        assert_eq!(
+            third.welcome.unwrap().message,
+            "Welcome! There are 2 other players online. 1 of them are in your group."
+        );
+
+        let status = third.status.unwrap();
+        assert_eq!((status.total_players, status.group_players), (3, 2));
+    });
+}
+
+#[test]
+fn outdated_client_is_rejected() {
+    run(async {
+        let server = TestServer::start().await;
+        let mut client = server.connect().await;
+        let error = client
+            .handshake(HandshakeRequest {
+                version: client::MIN_SUPPORTED_VERSION - 1,
+                ..handshake_request(1)
+            })
+            .await
+            .expect_err("Handshake should be rejected");
+        let rejection = error
+            .downcast_ref::<HandshakeRejected>()
+            .expect("Handshake failed without a response");
+
+        // Outdated clients can't decode the error code and only get the english description
+        assert_eq!(rejection.error, None);
+        assert_eq!(
+            rejection.error_message.as_deref(),
+            Some(HandshakeError::ClientOutdated.description())
+        );
+        assert_eq!(server.state().await.get_clients_iter().len(), 0);
+    });
+}
+
+#[test]
+fn version_is_negotiated_with_older_and_newer_clients() {
+    run(async {
+        let server = TestServer::start().await;
+        let older = server
+            .join_with(HandshakeRequest {
+                version: client::MIN_SUPPORTED_VERSION,
+                ..handshake_request(1)
+            })
+            .await;
+        let newer = server
+            .join_with(HandshakeRequest {
+                version: client::VERSION + 1,
+                ..handshake_request(2)
+            })
+            .await;
+
+        assert_eq!(older.version, client::MIN_SUPPORTED_VERSION);
+        assert_eq!(newer.version, client::VERSION);
+
+        let state = server.state().await;
+        let address = state.get_client_address(1).unwrap();
+        assert_eq!(
+            state.get_client(address).unwrap().version,
+            client::MIN_SUPPORTED_VERSION
+        );
+    });
+}
+
+#[test]
+fn invalid_ticket_is_rejected() {
+    run(async {
+        let server = TestServer::start().await;
+        let error = expect_rejection(
+            &server,
+            HandshakeRequest {
+                auth_session_ticket: vec![1, 2, 3],
+                ..handshake_request(1)
+            },
+        )
+        .await;
+
+        assert_eq!(error, Some(HandshakeError::AuthFailed));
+        assert_eq!(server.state().await.get_clients_iter().len(), 0);
+    });
+}
+
+#[test]
+fn compression_is_negotiated_in_handshake() {
+    run(async {
+        let server = TestServer::start().await;
+        let compressed = server.join(1).await;
+        let uncompressed = server
+            .join_with(HandshakeRequest {
+                compression: Vec::new(),
+                ..handshake_request(2)
+            })
+            .await;
+        let without_frame_flags = server
+            .join_with(HandshakeRequest {
+                version: codec::FRAME_FLAGS_VERSION - 1,
+                ..handshake_request(3)
+            })
+            .await;
+
+        assert_eq!(
+            compressed.client.compression(),
+            Some(CompressionAlgorithm::Deflate)
+        );
+        assert_eq!(uncompressed.client.compression(), None);
+        assert_eq!(without_frame_flags.client.compression(), None);
+    });
+}
+
+#[test]
+fn handshake_deadline_includes_proxy_header() {
+    run(async {
+        let server = TestServer::start_with(true).await;
+        let handshake_timeout = Duration::from_secs(config::get().limits.handshake_timeout);
+        let started_at = Instant::now();
+        let mut socket = TcpStream::connect(server.address()).await.unwrap();
+
+        // Each step alone takes less than the timeout, both together take longer
+        tokio::time::sleep(handshake_timeout * 3 / 5).await;
+        socket
+            .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 1234 4000\r\n")
+            .await
+            .unwrap();
+
+        // The server closes the connection without waiting for the first message
+        let mut buffer = [0; 1];
+        let read = tokio::time::timeout(handshake_timeout * 3 / 5, socket.read(&mut buffer))
+            .await
+            .expect("Connection was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(started_at.elapsed() >= handshake_timeout);
+    });
+}
+
+#[test]
+fn connections_without_client_address_are_closed() {
+    run(async {
+        let server = TestServer::start_with(true).await;
+        let mut socket = TcpStream::connect(server.address()).await.unwrap();
+        socket.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+
+        // Closed right after the header instead of waiting for the handshake deadline
+        let mut buffer = [0; 1];
+        let handshake_timeout = Duration::from_secs(config::get().limits.handshake_timeout);
+        let read = tokio::time::timeout(handshake_timeout / 2, socket.read(&mut buffer))
+            .await
+            .expect("Connection was not closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    });
+}
@@ -0,0 +1,245 @@
+//! In-process integration tests. Every test starts its own server on an ephemeral port
+//! and drives it with scripted clients, while steam is replaced by a fake api.
+
+use std::{future::Future, net::SocketAddr, sync::Arc, sync::Once, time::Duration};
+
+use futures::StreamExt;
+use jkmp_client::{Client, Event};
+use lazy_static::lazy_static;
+use tokio::{net::TcpListener, runtime::Runtime, sync::Mutex, task::JoinHandle};
+
+use crate::{
+    client, codec,
+    config::{self, Config},
+    math::Vector2,
+    messages::{
+        HandshakeRequest, OutgoingChatMessage, PositionUpdate, RequestMessage, ServerStatusUpdate,
+    },
+    state::State,
+};
+
+mod chat;
+mod fake_steam;
+mod groups;
+mod handshake;
+mod telemetry;
+
+/// Time to wait for an expected message or state change before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    // Tests share one runtime since the http client of the steam api outlives any single test
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+}
+
+static INIT: Once = Once::new();
+
+/// Runs the test on the shared runtime, configuring the server on first use
+fn run<F: Future>(test: F) -> F::Output {
+    configure();
+    RUNTIME.block_on(test)
+}
+
+/// Starts the fake steam api and sets the server config used by every test, only once per process
+fn configure() {
+    INIT.call_once(|| {
+        let steam_url = RUNTIME
+            .block_on(fake_steam::start())
+            .expect("Failed to start fake steam api");
+
+        let mut config = Config::default();
+        config.steam.api_key = Some("test".to_string());
+        config.steam.api_url = steam_url;
+        // Clients are removed as soon as they disconnect
+        config.protocol.resume_grace_period = 0;
+        // Every client connects from the same address
+        config.limits.max_connections_per_ip = usize::MAX;
+        config.limits.max_handshakes_per_minute = u32::MAX;
+        // Short enough for testing the deadline, long enough for the handshakes of the other tests
+        config.limits.handshake_timeout = 2;
+        config::set(config);
+    });
+}
+
+pub struct TestServer {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(false).await
+    }
+
+    /// Starts a server that expects a PROXY protocol header at the start of every connection
+    pub async fn start_with(proxy_protocol: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind listener");
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::new()));
+        let accept_state = state.clone();
+        let accept_task = tokio::spawn(async move {
+            crate::accept_clients(&listener, proxy_protocol, None, accept_state).await;
+        });
+
+        Self {
+            address,
+            state,
+            accept_task,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Opens a connection without sending a handshake
+    pub async fn connect(&self) -> Client {
+        Client::connect(self.address)
+            .await
+            .expect("Failed to connect")
+    }
+
+    /// Connects and completes the handshake with the default level and no password
+    pub async fn join(&self, steam_id: u64) -> TestClient {
+        self.join_with(handshake_request(steam_id)).await
+    }
+
+    /// Connects and completes the handshake, consuming the messages sent after it up to the status update
+    pub async fn join_with(&self, request: HandshakeRequest) -> TestClient {
+        let steam_id = fake_steam::decode_ticket(&request.auth_session_ticket)
+            .expect("Ticket is not accepted by the fake steam api");
+        let mut client = self.connect().await;
+        let version = client.handshake(request).await.expect("Handshake failed");
+
+        let mut test_client = TestClient {
+            client,
+            steam_id,
+            version,
+            history: Vec::new(),
+            welcome: None,
+            status: None,
+        };
+
+        loop {
+            match test_client.next_event().await {
+                Event::SessionToken(_) => {}
+                Event::ChatHistory(message) => test_client.history.push(message),
+                Event::ChatMessage(message) => test_client.welcome = Some(message),
+                Event::StatusUpdate(status) => {
+                    test_client.status = Some(status);
+                    break;
+                }
+                event => panic!("Unexpected event after handshake: {:?}", event),
+            }
+        }
+
+        test_client
+    }
+
+    /// Waits until the condition holds for the server state, fails the test if it doesn't in time
+    pub async fn wait_until(&self, condition: impl Fn(&State) -> bool) {
+        let wait = async {
+            while !condition(&*self.state.lock().await) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for the server state");
+    }
+
+    pub async fn state(&self) -> tokio::sync::MutexGuard<'_, State> {
+        self.state.lock().await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Client that completed the handshake
+pub struct TestClient {
+    pub client: Client,
+    pub steam_id: u64,
+    pub version: u32,
+    /// Chat messages replayed after the handshake
+    pub history: Vec<OutgoingChatMessage>,
+    pub welcome: Option<OutgoingChatMessage>,
+    pub status: Option<ServerStatusUpdate>,
+}
+
+impl TestClient {
+    /// Returns the next event, fails the test if none arrives in time
+    pub async fn next_event(&mut self) -> Event {
+        match tokio::time::timeout(TIMEOUT, self.client.next()).await {
+            Ok(Some(Ok(event))) => event,
+            Ok(Some(Err(error))) => panic!("Failed to receive event: {:?}", error),
+            Ok(None) => panic!("Connection closed by the server"),
+            Err(_) => panic!("Timed out waiting for an event"),
+        }
+    }
+
+    /// Returns the next event, fails the test if it isn't a chat message
+    pub async fn next_chat_message(&mut self) -> OutgoingChatMessage {
+        match self.next_event().await {
+            Event::ChatMessage(message) => message,
+            event => panic!("Expected a chat message, received {:?}", event),
+        }
+    }
+
+    /// Sends the message as a request and returns the events received before it was acknowledged.
+    /// Since the server handles messages in order, all effects of the message on this client are included.
+    pub async fn request(&mut self, message: RequestMessage) -> Vec<Event> {
+        let id = self
+            .client
+            .send_request(message)
+            .await
+            .expect("Failed to send request");
+        let mut events = Vec::new();
+
+        loop {
+            match self.next_event().await {
+                Event::Ack(ack) if ack.id == id => return events,
+                Event::RequestError(error) if error.id == id => {
+                    panic!("Request failed: {:?}", error.code)
+                }
+                event => events.push(event),
+            }
+        }
+    }
+
+    /// Updates the position and returns the steam ids of the nearby players the server reported
+    pub async fn move_to(&mut self, position: Vector2) -> Vec<u64> {
+        let events = self
+            .request(RequestMessage::PositionUpdate(PositionUpdate { position }))
+            .await;
+
+        events
+            .into_iter()
+            .flat_map(|event| match event {
+                Event::NearbyClients(message) => message.client_ids,
+                event => panic!("Unexpected event after position update: {:?}", event),
+            })
+            .collect()
+    }
+}
+
+pub fn handshake_request(steam_id: u64) -> HandshakeRequest {
+    HandshakeRequest {
+        auth_session_ticket: fake_steam::ticket(steam_id),
+        matchmaking_password: None,
+        level_name: "Level".to_string(),
+        position: Vector2 { x: 0.0, y: 0.0 },
+        version: client::VERSION,
+        compression: codec::SUPPORTED_COMPRESSION.to_vec(),
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt};
+
+use super::{configure, fake_steam, TestServer, TIMEOUT};
+use crate::{config::TelemetryConfig, telemetry};
+
+/// Spans of a handshake that are expected to be exported
+const HANDSHAKE_SPANS: [&str; 4] = [
+    "verify_user_auth_ticket",
+    "get_player_summaries",
+    "lock_state",
+    "send_response",
+];
+
+/// Fields the handshake records on the span of the connection
+const CONNECTION_FIELDS: [&str; 4] = ["steam_id", "player_name", "protocol_version", "group"];
+
+/// Starts a minimal OTLP/HTTP receiver that keeps the bodies of all export requests, returns its url
+async fn start_receiver(received: Arc<Mutex<Vec<u8>>>) -> Result<String, anyhow::Error> {
+    let make_service = make_service_fn(move |_| {
+        let received = received.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let received = received.clone();
+
+                async move {
+                    let body = hyper::body::to_bytes(request.into_body()).await?;
+                    received.lock().unwrap().extend_from_slice(&body);
+                    Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+    let url = format!("http://{}/v1/traces", server.local_addr());
+    tokio::spawn(server);
+
+    Ok(url)
+}
+
+/// Returns true once every string appears in the received requests.
+/// The protobuf payload isn't decoded, span names and attributes are stored as plain strings in it.
+fn contains_strings(received: &[u8], names: &[&str]) -> bool {
+    names.iter().all(|name| {
+        received
+            .windows(name.len())
+            .any(|window| window == name.as_bytes())
+    })
+}
+
+#[test]
+fn handshake_spans_are_exported() {
+    configure();
+
+    // A single thread runtime so every task of the server is traced by the subscriber of this thread
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let endpoint = start_receiver(received.clone()).await.unwrap();
+        let tracer = telemetry::init_tracer(&TelemetryConfig {
+            otlp_endpoint: Some(endpoint),
+            // Keeps the test from waiting for the default delay of 5 seconds
+            export_delay_ms: 50,
+            ..TelemetryConfig::default()
+        })
+        .unwrap()
+        .expect("Tracer should be created for an endpoint");
+
+        // Only spans of the server, the exporter would otherwise trace its own requests
+        let subscriber = tracing_subscriber::registry()
+            .with(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::TRACE))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server = TestServer::start().await;
+        // A steam id that no other test uses so the player summary isn't cached yet
+        let player = server.join(33).await;
+        // The connection span is only exported once it's closed
+        drop(player);
+
+        let player_name = fake_steam::player_name(33);
+        let expected: Vec<&str> = HANDSHAKE_SPANS
+            .iter()
+            .chain(&["connection", player_name.as_str()])
+            .chain(&CONNECTION_FIELDS)
+            .copied()
+            .collect();
+
+        let wait = async {
+            while !contains_strings(&received.lock().unwrap(), &expected) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for the handshake spans and connection fields");
+    });
+}
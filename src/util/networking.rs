@@ -1,16 +1,16 @@
 use futures::SinkExt;
-use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
 use crate::{
     client::Client,
     codec::MessagesCodec,
     messages::{InformNearbyClients, Message},
+    stream::ClientStream,
 };
 
 pub async fn send_nearby_clients(
     except_steam_id: &u64,
-    messages: &mut Framed<TcpStream, MessagesCodec>,
+    messages: &mut Framed<ClientStream, MessagesCodec>,
     clients: &[&Client],
 ) -> Result<(), anyhow::Error> {
     let nearby_client_ids: Vec<u64> = clients